@@ -0,0 +1,176 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use ring::digest;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{timeout, Duration};
+
+use crate::proxy::{read_http_head, HttpHead};
+use crate::utils::tcp_listen;
+
+const ADMIN_REQUEST_TIMEOUT: u64 = 10;
+const ADMIN_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// 管理接口的响应, 响应体一般为 JSON
+pub struct AdminResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl AdminResponse {
+    pub fn ok<T: Serialize>(body: T) -> AdminResponse {
+        Self::json(200, serde_json::to_value(body).unwrap_or_default())
+    }
+
+    pub fn error(status: u16, message: &str) -> AdminResponse {
+        Self::json(status, serde_json::json!({ "error": message }))
+    }
+
+    /// Prometheus 文本格式的响应
+    pub fn text(body: String) -> AdminResponse {
+        AdminResponse {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn json(status: u16, body: serde_json::Value) -> AdminResponse {
+        AdminResponse {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec_pretty(&body).unwrap_or_default(),
+        }
+    }
+
+    pub fn not_found() -> AdminResponse {
+        Self::error(404, "not found")
+    }
+}
+
+pub type AdminFuture<'a> = Pin<Box<dyn Future<Output = AdminResponse> + Send + 'a>>;
+
+/// 管理接口的请求处理, 服务端和客户端各自实现
+pub trait AdminHandler: Send + Sync {
+    /// `path` 不含查询参数, 按 `/` 分割后的各段. `body` 为请求体, 没有时为空
+    fn handle<'a>(&'a self, method: &'a str, path: &'a [&'a str], body: &'a [u8]) -> AdminFuture<'a>;
+}
+
+/// 解析 JSON 请求体, 失败时返回 400 响应
+pub fn admin_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, AdminResponse> {
+    serde_json::from_slice(body).map_err(|e| AdminResponse::error(400, &format!("invalid body: {}", e)))
+}
+
+/// 启动管理接口, `quit_rx` 变化或发送端释放时停止监听
+pub fn start_admin(listen: SocketAddr, token: Option<String>, handler: Arc<dyn AdminHandler>, mut quit_rx: watch::Receiver<String>) -> io::Result<()> {
+    let listener = tcp_listen(listen)?;
+    log::info!("admin api listen on: {}", listen);
+    if token.is_none() {
+        log::warn!("admin api has no admin_token, anyone who can reach {} can control this node", listen);
+    }
+
+    let token = Arc::new(token);
+    tokio::spawn(async move {
+        loop {
+            select! {
+                accept_result = listener.accept() => {
+                    let Ok((socket, peer_addr)) = accept_result else {
+                        continue;
+                    };
+                    tokio::spawn(admin_conn(socket, peer_addr, token.clone(), handler.clone()));
+                },
+                _ = quit_rx.changed() => {
+                    log::debug!("admin api recv app quit msg");
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn admin_conn(mut socket: TcpStream, peer_addr: SocketAddr, token: Arc<Option<String>>, handler: Arc<dyn AdminHandler>) {
+    let request = match timeout(Duration::from_secs(ADMIN_REQUEST_TIMEOUT), admin_read_request(&mut socket)).await {
+        Ok(request) => request,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "read request timeout")),
+    };
+    let rsp = match request {
+        Ok((head, body)) => admin_dispatch(&head, &body, &token, handler.as_ref()).await,
+        Err(e) => {
+            log::debug!("admin request from {} error: {}", peer_addr, e);
+            AdminResponse::error(400, &format!("bad request: {}", e))
+        }
+    };
+    if let Err(e) = socket.write_all(&admin_encode(rsp)).await {
+        log::debug!("admin response to {} error: {}", peer_addr, e);
+    }
+    socket.shutdown().await.unwrap_or(());
+}
+
+/// 读取请求头和 `Content-Length` 指定长度的请求体
+async fn admin_read_request(socket: &mut TcpStream) -> io::Result<(HttpHead, Vec<u8>)> {
+    let (head, mut body) = read_http_head(socket).await?;
+    let head = HttpHead::parse(&head)?;
+    let length = match head.get("Content-Length") {
+        Some(x) => x.trim().parse::<usize>().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid content length"))?,
+        None => 0,
+    };
+    if length > ADMIN_MAX_BODY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    if body.len() < length {
+        let read = body.len();
+        body.resize(length, 0);
+        socket.read_exact(&mut body[read..]).await?;
+    }
+    body.truncate(length);
+    Ok((head, body))
+}
+
+async fn admin_dispatch(head: &HttpHead, body: &[u8], token: &Option<String>, handler: &dyn AdminHandler) -> AdminResponse {
+    if let Some(token) = token {
+        let presented = head.get("Authorization").and_then(|x| x.strip_prefix("Bearer ")).unwrap_or_default();
+        // 比较摘要, 避免逐字节比较泄露令牌长度和内容
+        if digest::digest(&digest::SHA256, presented.as_bytes()).as_ref() != digest::digest(&digest::SHA256, token.as_bytes()).as_ref() {
+            return AdminResponse::error(401, "unauthorized");
+        }
+    }
+
+    let Ok((method, target, _)) = head.request_line() else {
+        return AdminResponse::error(400, "bad request");
+    };
+    let path = target.split('?').next().unwrap_or_default();
+    let path: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+    log::debug!("admin request: {} {}", method, target);
+    handler.handle(method, &path, body).await
+}
+
+fn admin_encode(rsp: AdminResponse) -> Vec<u8> {
+    let reason = match rsp.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Error",
+    };
+    let mut head = HttpHead {
+        first_line: format!("HTTP/1.1 {} {}", rsp.status, reason),
+        headers: vec![],
+    };
+    head.add("Content-Type", rsp.content_type);
+    head.add("Content-Length", &rsp.body.len().to_string());
+    head.add("Connection", "close");
+    let mut data = head.encode();
+    data.extend_from_slice(&rsp.body);
+    data
+}
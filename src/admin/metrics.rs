@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::TrafficStats;
+
+#[derive(Default)]
+struct MetricsInner {
+    tls_handshake_failures: AtomicU64,
+    reconnects: AtomicU64,
+    /// 按映射统计的等待转发通道超时次数
+    bind_timeouts: Mutex<HashMap<String, u64>>,
+}
+
+/// 会话以外的监控计数, 会话和流量由 `SessionTable` 统计
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<MetricsInner>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tls_handshake_failed(&self) {
+        self.inner.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.inner.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bind_timeout(&self, mapping: &str) {
+        *self.inner.bind_timeouts.lock().unwrap().entry(mapping.to_string()).or_default() += 1;
+    }
+
+    pub fn tls_handshake_failures(&self) -> u64 {
+        self.inner.tls_handshake_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.inner.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn bind_timeouts(&self) -> Vec<(String, u64)> {
+        let mut list: Vec<(String, u64)> = self.inner.bind_timeouts.lock().unwrap().iter().map(|(k, v)| (k.clone(), *v)).collect();
+        list.sort();
+        list
+    }
+}
+
+/// Prometheus 文本格式的输出
+#[derive(Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 写入一个指标, `kind` 为 counter 或 gauge, 每个样本为标签和值
+    pub fn metric(&mut self, name: &str, kind: &str, help: &str, samples: &[(Vec<(&str, &str)>, u64)]) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap_or(());
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap_or(());
+        for (labels, value) in samples {
+            if labels.is_empty() {
+                writeln!(self.out, "{} {}", name, value).unwrap_or(());
+                continue;
+            }
+            let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v))).collect();
+            writeln!(self.out, "{}{{{}}} {}", name, labels.join(","), value).unwrap_or(());
+        }
+    }
+
+    /// 按映射和客户端统计的会话和流量
+    pub fn traffic(&mut self, traffic: &[TrafficStats]) {
+        let samples = |value: fn(&TrafficStats) -> u64| -> Vec<(Vec<(&str, &str)>, u64)> {
+            traffic.iter().map(|x| (vec![("mapping", x.mapping.as_str()), ("client", x.client.as_str())], value(x))).collect()
+        };
+        self.metric("natproxy_sessions_active", "gauge", "Sessions being forwarded.", &samples(|x| x.active));
+        self.metric("natproxy_sessions_total", "counter", "Sessions routed to a client.", &samples(|x| x.sessions));
+        self.metric("natproxy_bytes_in_total", "counter", "Bytes sent from the initiator towards the target.", &samples(|x| x.bytes_in));
+        self.metric("natproxy_bytes_out_total", "counter", "Bytes returned from the target to the initiator.", &samples(|x| x.bytes_out));
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_exposition_format() {
+        let mut writer = MetricsWriter::new();
+        writer.metric("natproxy_reconnects_total", "counter", "Client reconnects.", &[(vec![], 3)]);
+        writer.metric("natproxy_bind_timeouts_total", "counter", "Bind timeouts.", &[
+            (vec![("mapping", "web")], 1),
+            (vec![("mapping", "a\"b\\c\nd"), ("client", "client1")], 2),
+        ]);
+        assert_eq!(writer.finish(), "\
+# HELP natproxy_reconnects_total Client reconnects.
+# TYPE natproxy_reconnects_total counter
+natproxy_reconnects_total 3
+# HELP natproxy_bind_timeouts_total Bind timeouts.
+# TYPE natproxy_bind_timeouts_total counter
+natproxy_bind_timeouts_total{mapping=\"web\"} 1
+natproxy_bind_timeouts_total{mapping=\"a\\\"b\\\\c\\nd\",client=\"client1\"} 2
+");
+    }
+
+    #[test]
+    fn metric_without_samples_keeps_metadata() {
+        let mut writer = MetricsWriter::new();
+        writer.metric("natproxy_sessions_active", "gauge", "Sessions being forwarded.", &[]);
+        assert_eq!(writer.finish(), "# HELP natproxy_sessions_active Sessions being forwarded.\n# TYPE natproxy_sessions_active gauge\n");
+    }
+
+    #[test]
+    fn traffic_samples_are_labeled() {
+        let mut writer = MetricsWriter::new();
+        writer.traffic(&[TrafficStats {
+            mapping: String::from("web"),
+            client: String::from("client1"),
+            active: 1,
+            sessions: 5,
+            bytes_in: 100,
+            bytes_out: 200,
+        }]);
+        let text = writer.finish();
+        assert!(text.contains("# TYPE natproxy_sessions_active gauge\n"));
+        assert!(text.contains("natproxy_sessions_total{mapping=\"web\",client=\"client1\"} 5\n"));
+        assert!(text.contains("natproxy_bytes_in_total{mapping=\"web\",client=\"client1\"} 100\n"));
+        assert!(text.contains("natproxy_bytes_out_total{mapping=\"web\",client=\"client1\"} 200\n"));
+    }
+
+    #[test]
+    fn counters() {
+        let metrics = Metrics::new();
+        metrics.reconnected();
+        metrics.tls_handshake_failed();
+        metrics.tls_handshake_failed();
+        metrics.bind_timeout("web");
+        metrics.bind_timeout("tcp");
+        metrics.bind_timeout("web");
+        assert_eq!(metrics.reconnects(), 1);
+        assert_eq!(metrics.tls_handshake_failures(), 2);
+        assert_eq!(metrics.bind_timeouts(), vec![(String::from("tcp"), 1), (String::from("web"), 2)]);
+    }
+}
@@ -0,0 +1,7 @@
+mod api;
+mod sessions;
+mod metrics;
+
+pub use api::*;
+pub use sessions::*;
+pub use metrics::*;
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::utils::{get_datetime, StreamCounter};
+
+/// 转发会话信息, 字节数以发起方为准: bytes_in 为发往目标的数据, bytes_out 为返回的数据
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub bind_id: String,
+    pub mapping: String,
+    pub client: String,
+    /// 服务端为用户地址, 客户端为连接的目标地址
+    pub peer: String,
+    pub started_at: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// 按映射和客户端汇总的会话和流量, 包括已结束的会话
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TrafficStats {
+    pub mapping: String,
+    pub client: String,
+    pub active: u64,
+    pub sessions: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+struct SessionEntry {
+    info: SessionInfo,
+    counter: StreamCounter,
+    close: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct SessionState {
+    sessions: HashMap<String, SessionEntry>,
+    /// 已结束会话的流量和会话总数, 以 (映射, 客户端) 为键
+    totals: HashMap<(String, String), TrafficStats>,
+}
+
+/// 正在转发的会话, 以 bind_id 为键
+#[derive(Clone, Default)]
+pub struct SessionTable {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 登记会话, 返回的句柄释放时移除
+    pub fn open(&self, bind_id: &str, peer: String) -> SessionGuard {
+        let counter = StreamCounter::new();
+        let close = Arc::new(Notify::new());
+        let info = SessionInfo {
+            bind_id: bind_id.to_string(),
+            mapping: String::new(),
+            client: String::new(),
+            peer,
+            started_at: get_datetime(),
+            bytes_in: 0,
+            bytes_out: 0,
+        };
+        self.state.lock().unwrap().sessions.insert(bind_id.to_string(), SessionEntry { info, counter: counter.clone(), close: close.clone() });
+        SessionGuard {
+            table: self.clone(),
+            bind_id: bind_id.to_string(),
+            counter,
+            close,
+        }
+    }
+
+    /// 选定映射和客户端后更新会话信息
+    pub fn set_route(&self, bind_id: &str, mapping: &str, client: &str) {
+        let mut state = self.state.lock().unwrap();
+        let Some(entry) = state.sessions.get_mut(bind_id) else {
+            return;
+        };
+        if !entry.info.mapping.is_empty() {
+            return;
+        }
+        entry.info.mapping = mapping.to_string();
+        entry.info.client = client.to_string();
+        state.totals.entry((mapping.to_string(), client.to_string())).or_insert_with(|| TrafficStats {
+            mapping: mapping.to_string(),
+            client: client.to_string(),
+            ..Default::default()
+        }).sessions += 1;
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let state = self.state.lock().unwrap();
+        let mut list: Vec<SessionInfo> = state.sessions.values().map(|entry| SessionInfo {
+            bytes_in: entry.counter.read(),
+            bytes_out: entry.counter.written(),
+            ..entry.info.clone()
+        }).collect();
+        list.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+        list
+    }
+
+    /// 按映射和客户端汇总的流量, 包括正在转发的会话
+    pub fn traffic(&self) -> Vec<TrafficStats> {
+        let state = self.state.lock().unwrap();
+        let mut totals = state.totals.clone();
+        for entry in state.sessions.values().filter(|x| !x.info.mapping.is_empty()) {
+            if let Some(stats) = totals.get_mut(&(entry.info.mapping.clone(), entry.info.client.clone())) {
+                stats.active += 1;
+                stats.bytes_in += entry.counter.read();
+                stats.bytes_out += entry.counter.written();
+            }
+        }
+        let mut list: Vec<TrafficStats> = totals.into_values().collect();
+        list.sort_by(|a, b| (&a.mapping, &a.client).cmp(&(&b.mapping, &b.client)));
+        list
+    }
+
+    /// 使用指定映射的会话
+    pub fn mapping_sessions(&self, mapping: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.sessions.values().filter(|x| x.info.mapping == mapping).map(|x| x.info.bind_id.clone()).collect()
+    }
+
+    /// 关闭会话, 会话不存在时返回 false
+    pub fn close(&self, bind_id: &str) -> bool {
+        match self.state.lock().unwrap().sessions.get(bind_id) {
+            Some(entry) => {
+                entry.close.notify_one();
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// 会话句柄, 转发任务持有期间会话保持登记
+pub struct SessionGuard {
+    table: SessionTable,
+    bind_id: String,
+    counter: StreamCounter,
+    close: Arc<Notify>,
+}
+
+impl SessionGuard {
+    /// 统计用户一侧读写字节数的计数器
+    pub fn counter(&self) -> StreamCounter {
+        self.counter.clone()
+    }
+
+    /// 等待会话被关闭, 可以在 `select!` 中使用
+    pub async fn closed(&self) {
+        self.close.notified().await
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut state = self.table.state.lock().unwrap();
+        let Some(entry) = state.sessions.remove(&self.bind_id) else {
+            return;
+        };
+        if let Some(stats) = state.totals.get_mut(&(entry.info.mapping, entry.info.client)) {
+            stats.bytes_in += entry.counter.read();
+            stats.bytes_out += entry.counter.written();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn close_sessions_of_mapping() {
+        let table = SessionTable::new();
+        let session1 = table.open("id1", String::from("127.0.0.1:1000"));
+        let _session2 = table.open("id2", String::from("127.0.0.1:1001"));
+        table.set_route("id1", "web", "client1");
+        table.set_route("id2", "tcp", "client1");
+
+        assert_eq!(table.mapping_sessions("web"), vec![String::from("id1")]);
+        assert!(table.close("id1"));
+        session1.closed().await;
+        assert!(!table.close("unknown"));
+
+        drop(session1);
+        assert!(table.mapping_sessions("web").is_empty());
+        assert_eq!(table.list().len(), 1);
+    }
+}
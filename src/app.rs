@@ -1,11 +1,16 @@
-use tokio::sync::{mpsc, watch};
+use tokio::select;
+use tokio::sync::watch;
+use std::io;
+use std::sync::Arc;
 use tokio::time::{
-    sleep, Duration
+    sleep, Duration, Instant
 };
-use tokio::select;
 
-use crate::client::start_client_node;
+use crate::admin::start_admin;
+use crate::reload::{reconnect_required, start_config_reload};
+use crate::client::{client_failback, start_client_node, ClientAdmin, ClientState};
 use crate::server::start_server_node;
+use crate::utils::{set_prefer_family, Backoff};
 
 
 use crate::{
@@ -18,7 +23,8 @@ pub struct App {
 }
 
 const SERVER_CONNECTION_RESET_TIMEOUT:u64 = 3;
-const CLIENT_CONNECTION_RESET_TIMEOUT:u64 = 5;
+/// 连接保持超过该秒数视为稳定, 之后断开时重新从初始等待时间开始重连
+const CLIENT_STABLE_CONNECTION_TIME:u64 = 30;
 
 impl App {
     pub fn new(option: AppOption) -> App {
@@ -29,12 +35,19 @@ impl App {
 
     pub async fn start(&mut self) -> AppResult<()> {
         //let (tx, mut rx) = mpsc::channel::<mpsc::Sender<String>>(32);
+        self.option.validate()?;
+        set_prefer_family(self.option.prefer_ip.parse()?);
 
+        let mut reload_rx = start_config_reload(&self.option);
         if self.option.role == "server" {
             loop {
                 let (main_cli_tx, main_cli_rx) = watch::channel::<String>(String::from("cmd"));
+                // 重启时使用最近一次加载的配置
+                self.option = reload_rx.borrow_and_update().clone();
 
-                let result = start_server_node(self.option.clone(), main_cli_rx).await;
+                if let Err(e) = start_server_node(self.option.clone(), main_cli_rx, reload_rx.clone()).await {
+                    log::error!("Server node error: {:?}", e);
+                }
                 main_cli_tx.send(String::from("app-quit")).unwrap_or(());
                 log::info!("Server node stoped.");
                 log::info!("Reset server for new connection after {} seconds.", SERVER_CONNECTION_RESET_TIMEOUT);
@@ -42,13 +55,85 @@ impl App {
             }
 
         } else {
+            let mut backoff = Backoff::new(
+                Duration::from_secs(self.option.reconnect_delay),
+                Duration::from_secs(self.option.reconnect_max_delay),
+                self.option.reconnect_multiplier,
+                self.option.reconnect_jitter,
+            );
+            let state = ClientState::new(&self.option);
+            // 管理接口在重连之间保持监听
+            let (_admin_tx, admin_rx) = watch::channel::<String>(String::from("cmd"));
+            if let Some(admin_listen) = self.option.admin_listen {
+                start_admin(admin_listen, self.option.admin_token.clone(), Arc::new(ClientAdmin { state: state.clone() }), admin_rx)?;
+            }
+            let mut index = 0;
+            let mut reconnect = false;
             loop {
-                let result = start_client_node(self.option.clone()).await?;
+                if reconnect {
+                    state.metrics.reconnected();
+                }
+                reconnect = true;
+                self.option = reload_rx.borrow_and_update().clone();
+                let endpoints = self.option.server_endpoints();
+                if index >= endpoints.len() {
+                    index = 0;
+                }
+                let mut option = self.option.clone();
+                if let Some(server) = endpoints.get(index) {
+                    option.server = Some(server.clone());
+                }
+                let preferred = if self.option.server_failback { &endpoints[..index] } else { &[] };
+
+                let started = Instant::now();
+                let result = select! {
+                    result = start_client_node(option, state.clone(), reload_rx.clone()) => result,
+                    preferred_index = client_failback(&self.option, preferred), if !preferred.is_empty() => {
+                        log::info!("Fail back to server {}", endpoints[preferred_index]);
+                        index = preferred_index;
+                        backoff.reset();
+                        continue;
+                    },
+                    _ = client_wait_reconnect(&mut reload_rx, &self.option) => {
+                        log::info!("Config changed, reconnect with the new config");
+                        backoff.reset();
+                        continue;
+                    }
+                };
+                if let Err(e) = result {
+                    log::error!("Client node error: {:?}", e);
+                }
                 log::info!("Client node stoped.");
-                log::info!("Create new connection after {} seconds", CLIENT_CONNECTION_RESET_TIMEOUT);
-                sleep(Duration::from_secs(CLIENT_CONNECTION_RESET_TIMEOUT)).await;
+
+                if started.elapsed() >= Duration::from_secs(CLIENT_STABLE_CONNECTION_TIME) {
+                    backoff.reset();
+                } else if endpoints.len() > 1 {
+                    // 依次尝试其它服务端, 全部失败后再等待
+                    index = (index + 1) % endpoints.len();
+                    log::info!("Fail over to server {}", endpoints[index]);
+                    if index != 0 {
+                        continue;
+                    }
+                }
+                let max_attempts = self.option.reconnect_max_attempts;
+                if max_attempts > 0 && backoff.attempts() >= max_attempts {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("client reconnect failed after {} attempts", max_attempts)).into());
+                }
+                let delay = backoff.next_delay();
+                log::info!("Create new connection after {:.1} seconds", delay.as_secs_f64());
+                sleep(delay).await;
             }
         }
 
     }
 }
+
+/// 等待需要重新连接的配置变化, 映射变化由信令连接重新声明
+async fn client_wait_reconnect(reload_rx: &mut watch::Receiver<AppOption>, current: &AppOption) {
+    while reload_rx.changed().await.is_ok() {
+        if reconnect_required(current, &reload_rx.borrow()) {
+            return;
+        }
+    }
+    std::future::pending().await
+}
@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::admin::{AdminFuture, AdminHandler, AdminResponse, Metrics, MetricsWriter, SessionTable};
+use crate::utils::get_datetime;
+use crate::{AppOption, MappingConfig};
+
+use super::client_name_of;
+
+/// 客户端当前的连接状态
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ClientStatus {
+    pub name: String,
+    /// 当前连接的服务端, 被动模式下为服务端地址
+    pub server: Option<String>,
+    pub connected: bool,
+    pub version: Option<u32>,
+    pub connected_at: Option<String>,
+    /// 向服务端声明的映射
+    pub mappings: Vec<MappingConfig>,
+    /// 服务端对声明映射的处理结果
+    pub mappings_status: Option<String>,
+}
+
+/// 客户端在重连之间保持的状态, 供管理接口查询
+#[derive(Clone, Default)]
+pub struct ClientState {
+    status: Arc<Mutex<ClientStatus>>,
+    pub sessions: SessionTable,
+    pub metrics: Metrics,
+}
+
+impl ClientState {
+    pub fn new(option: &AppOption) -> Self {
+        let status = ClientStatus {
+            name: client_name_of(option),
+            mappings: option.mappings.clone(),
+            ..Default::default()
+        };
+        ClientState {
+            status: Arc::new(Mutex::new(status)),
+            sessions: SessionTable::new(),
+            metrics: Metrics::new(),
+        }
+    }
+
+    pub fn connected(&self, server: String, version: u32) {
+        let mut status = self.status.lock().unwrap();
+        status.server = Some(server);
+        status.connected = true;
+        status.version = Some(version);
+        status.connected_at = Some(get_datetime());
+        status.mappings_status = None;
+    }
+
+    pub fn disconnected(&self) {
+        self.status.lock().unwrap().connected = false;
+    }
+
+    /// 重新声明映射, 等待服务端的处理结果
+    pub fn set_mappings(&self, mappings: Vec<MappingConfig>) {
+        let mut status = self.status.lock().unwrap();
+        status.mappings = mappings;
+        status.mappings_status = None;
+    }
+
+    pub fn set_mappings_status(&self, message: String) {
+        self.status.lock().unwrap().mappings_status = Some(message);
+    }
+
+    pub fn status(&self) -> ClientStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Prometheus 格式的监控指标
+    pub fn metrics_text(&self) -> String {
+        let mut writer = MetricsWriter::new();
+        let connected = self.status.lock().unwrap().connected as u64;
+        writer.metric("natproxy_server_connected", "gauge", "Whether the client is connected to a server.", &[(vec![], connected)]);
+        writer.metric("natproxy_reconnects_total", "counter", "Reconnections to the server.", &[(vec![], self.metrics.reconnects())]);
+        writer.metric("natproxy_tls_handshake_failures_total", "counter", "Failed TLS handshakes with the server.", &[(vec![], self.metrics.tls_handshake_failures())]);
+        writer.traffic(&self.sessions.traffic());
+        writer.finish()
+    }
+}
+
+/// 客户端管理接口
+///
+/// - `GET /status`: 连接状态和声明的映射
+/// - `GET /sessions`: 正在转发的会话
+/// - `DELETE /sessions/{bind_id}`: 关闭会话
+/// - `GET /metrics`: Prometheus 格式的监控指标
+pub struct ClientAdmin {
+    pub state: ClientState,
+}
+
+impl AdminHandler for ClientAdmin {
+    fn handle<'a>(&'a self, method: &'a str, path: &'a [&'a str], _body: &'a [u8]) -> AdminFuture<'a> {
+        Box::pin(async move {
+            match (method, path) {
+                ("GET", ["status"]) => AdminResponse::ok(self.state.status()),
+                ("GET", ["sessions"]) => AdminResponse::ok(self.state.sessions.list()),
+                ("DELETE", ["sessions", bind_id]) => {
+                    if self.state.sessions.close(bind_id) {
+                        AdminResponse::ok(json!({ "closed": bind_id }))
+                    } else {
+                        AdminResponse::not_found()
+                    }
+                },
+                ("GET", ["metrics"]) => AdminResponse::text(self.state.metrics_text()),
+                (_, ["status"] | ["sessions"] | ["sessions", _] | ["metrics"]) => AdminResponse::error(405, "method not allowed"),
+                _ => AdminResponse::not_found(),
+            }
+        })
+    }
+}
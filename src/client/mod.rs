@@ -1,3 +1,5 @@
 mod node_client;
+mod admin;
 
-pub use node_client::*;
\ No newline at end of file
+pub use node_client::*;
+pub use admin::*;
@@ -1,188 +1,492 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::utils::{lookup_ipv4, new_tls_stream, generate_uuid};
+use crate::utils::{new_tls_stream, tls_client_handshake, generate_uuid, load_cert_identity, tcp_connect, tcp_listen};
 use crate::proto;
-use serde_json;
-use tokio::select;
+use crate::proxy;
+use crate::mux::{new_mux_session, MuxMode, MuxStream};
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
 use tokio_rustls::client::TlsStream as TlsClientStream;
+use crate::admin::{Metrics, SessionGuard};
 use crate::{
-    tls_client_read_to, 
-    error::AppTypeResult, AppOption, AppResult,MappingConfig,AppError
+    stream_forward,
+    AsyncStream,
+    CountedStream,
+    AppOption, AppResult, MappingConfig
 };
 
-const META_MSG_END_FLAG: [u8;1] = [0;1];
+use super::ClientState;
+
+const MUX_CHANNEL_RECONNECT_TIMEOUT: u64 = 3;
+const CONNECTION_HANDSHAKE_TIMEOUT: u64 = 10;
+const VERSION_REPLY_TIMEOUT: u64 = 5;
+const CLIENT_RSP_QUEUE_SIZE: usize = 100;
+const SERVER_FAILBACK_CHECK_INTERVAL: u64 = 30;
+
+pub(crate) fn client_name_of(option: &AppOption) -> String {
+    // 未配置名称时使用证书名称, 服务端会以证书身份为准校验
+    option.client_name.clone()
+        .or_else(|| option.cert.as_deref().and_then(load_cert_identity).and_then(|x| x.name().map(|x| x.to_string())))
+        .unwrap_or_default()
+}
 
-pub async fn start_client_node(option: AppOption) -> AppResult<()> {
+/// 连接服务端并处理信令, `reload_rx` 中的映射变化会重新向服务端声明
+pub async fn start_client_node(option: AppOption, state: ClientState, reload_rx: watch::Receiver<AppOption>) -> AppResult<()> {
     log::debug!("proxy client running ...");
+    if option.is_passive() {
+        return start_passive_client_node(option, state, reload_rx).await;
+    }
+
     let ca_file = option.ca_cert.clone().unwrap();
     let cert_file = option.cert.clone().unwrap();
     let key_file = option.key.clone().unwrap();
 
-    log::info!("connect to server: {}", option.server.unwrap().to_string());
-    let server_signal_addr = SocketAddr::new(option.server.clone().unwrap(), option.signal_port);
-    let mut tls_stream = new_tls_stream("localhost", server_signal_addr, &ca_file, &cert_file, &key_file).await;
+    let server_signal_addr = option.server_signal_addr().unwrap();
+    log::info!("connect to server: {}", server_signal_addr);
+    let socket = tcp_connect(&server_signal_addr, false).await?;
+    let mut tls_stream = client_tls_handshake(&state.metrics, &option.server_tls_name(), socket, &ca_file, &cert_file, &key_file).await?;
     let client_id = generate_uuid();
-    let client_name = String::from("client1");
+    let client_name = client_name_of(&option);
 
-    let meta_msg:String = format!("main:{}:{}", client_name, client_id);
-    tls_stream.write(meta_msg.clone().as_bytes()).await.unwrap();
-    tls_stream.write(&[0; 1]).await.unwrap();
+    let meta_msg:String = format!("main:{}:{}:{}", client_name, client_id, proto::CMD_VERSION);
+    proto::write_meta(&mut tls_stream, &meta_msg).await?;
+    let mut recv_buffer: Vec<u8> = Vec::new();
+    let version = client_read_version(&mut tls_stream, &mut recv_buffer).await?;
+    log::info!("server connected, protocol version {}", version);
 
-    loop {
-        let mut recv_buffer: Vec<u8> = Vec::new();
-        let result = tls_client_read_to(&mut tls_stream, &mut recv_buffer, 0).await;
-        match result {
-            Ok(size) => {
-                log::info!("main recv size: {}", size);
-            },
-            Err(e) => {
-                let err_kind = e.kind();
-                match err_kind {
-                    std::io::ErrorKind::UnexpectedEof => {
-                        log::info!("server connection closed");
-                        return Ok(());
-                    },
-                    _ => {
-                        log::error!("main error: {}", e);
-                        return Err(e.into());
-                    }
-                }
-                
-            }
-        }        
-        let res = String::from_utf8(recv_buffer).unwrap();
-        log::debug!("client read data: {}", res);
+    let mut mux_tasks = MuxTasks(vec![]);
+    if option.mux {
+        for _ in 0..option.mux_channels.max(1) {
+            mux_tasks.0.push(tokio::spawn(client_mux_channel(option.clone(), client_name.clone(), client_id.clone(), state.clone())));
+        }
+    }
 
-        let proto_cmd: proto::ProtoCmd = serde_json::from_str(&res).unwrap();
-        match proto_cmd {
-            proto::ProtoCmd::Request(req) => {
-                let status: String = String::from("Ok");
-                let message: String = String::from("proccess success");
-                if let Some(req_body) = req.body {
-                    match req_body {
-                        proto::ProtoCmdBody::ProxyRequest{bind_id, client, mapping} => {
-                            client_forward(option.clone(), bind_id, client, &mapping).await.unwrap();
-                        }
-                        _ => {
-                            //TODO:
-                        }
-                    }
-                }
-                
-                let rspcmd = proto::ProtoCmd::Response(proto::ProtoCmdResponse::new(req.id.clone(), req.cmd_type.clone(), status, message, None));
-                let json = serde_json::to_string(&rspcmd).unwrap();
-                log::debug!("client send data: {}", json);
-                tls_stream.write(json.as_bytes()).await.unwrap();
-                tls_stream.write(&META_MSG_END_FLAG).await.unwrap();
-            },
-            proto::ProtoCmd::Response(rsp) => {
+    state.connected(server_signal_addr, version);
+    let result = client_signal_loop(&option, &mut tls_stream, recv_buffer, &state, reload_rx).await;
+    state.disconnected();
+    result
+}
+
+/// 以客户端身份完成 TLS 握手, 失败时计入握手失败次数
+async fn client_tls_handshake(metrics: &Metrics, domain: &str, socket: TcpStream,
+    ca_file: &str, cert_file: &str, key_file: &str) -> std::io::Result<TlsClientStream<TcpStream>> {
+    let result = tls_client_handshake(domain, socket, ca_file, cert_file, key_file).await;
+    if result.is_err() {
+        metrics.tls_handshake_failed();
+    }
+    result
+}
+
+/// 读取服务端协商的协议版本. 旧服务端不回复版本, 超时或直接收到命令时按旧版本处理
+async fn client_read_version(tls_stream: &mut TlsClientStream<TcpStream>, recv_buffer: &mut Vec<u8>) -> std::io::Result<u32> {
+    match timeout(Duration::from_secs(VERSION_REPLY_TIMEOUT), proto::read_version(tls_stream, recv_buffer)).await {
+        Ok(result) => result,
+        Err(_) => {
+            log::warn!("server did not reply protocol version, fall back to version {}", proto::CMD_VERSION_LEGACY);
+            Ok(proto::CMD_VERSION_LEGACY)
+        }
+    }
+}
 
+/// 信令连接结束或被取消时结束多路复用连接任务
+struct MuxTasks(Vec<JoinHandle<()>>);
+
+impl Drop for MuxTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// 定期检查优先级更高的服务端, 返回第一个可以完成 TLS 握手的服务端序号
+pub async fn client_failback(option: &AppOption, endpoints: &[String]) -> usize {
+    let ca_file = option.ca_cert.clone().unwrap();
+    let cert_file = option.cert.clone().unwrap();
+    let key_file = option.key.clone().unwrap();
+
+    loop {
+        sleep(Duration::from_secs(SERVER_FAILBACK_CHECK_INTERVAL)).await;
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            let mut option = option.clone();
+            option.server = Some(endpoint.clone());
+            let server_signal_addr = option.server_signal_addr().unwrap();
+            let server_name = option.server_tls_name();
+            let probe = new_tls_stream(&server_name, &server_signal_addr, &ca_file, &cert_file, &key_file);
+            match timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), probe).await {
+                Ok(Ok(_)) => return index,
+                Ok(Err(e)) => log::debug!("server {} is still unavailable: {}", server_signal_addr, e),
+                Err(_) => log::debug!("server {} is still unavailable: handshake timeout", server_signal_addr),
             }
         }
     }
 }
 
-async fn client_forward(option: AppOption, bind_id:String, client:String, mapping: &MappingConfig) -> AppResult<()>  {
+/// 被动模式: 客户端监听信令端口, 由服务端连接过来, 服务端在握手后告知连接用途
+async fn start_passive_client_node(option: AppOption, state: ClientState, reload_rx: watch::Receiver<AppOption>) -> AppResult<()> {
+    let listen_addr = SocketAddr::new(option.listen, option.signal_port);
+    let listener = tcp_listen(listen_addr)?;
+    log::info!("passive client listen on: {}", listen_addr);
+
+    let client_name = client_name_of(&option);
+    // 当前信令连接的ID, 多路复用连接需要使用同一个ID
+    let client_id = Arc::new(Mutex::new(generate_uuid()));
+    loop {
+        let (socket, peer_addr) = listener.accept().await?;
+        log::debug!("accept server connection from {}", peer_addr);
+        tokio::spawn(client_passive_conn(option.clone(), socket, client_name.clone(), client_id.clone(), state.clone(), reload_rx.clone()));
+    }
+}
+
+async fn client_passive_conn(option: AppOption, socket: TcpStream, client_name: String, client_id: Arc<Mutex<String>>, state: ClientState
+    , reload_rx: watch::Receiver<AppOption>) {
+    let peer_addr = socket.peer_addr().map(|x| x.to_string()).unwrap_or_default();
     let ca_file = option.ca_cert.clone().unwrap();
     let cert_file = option.cert.clone().unwrap();
     let key_file = option.key.clone().unwrap();
-    
-    let server_data_addr = SocketAddr::new(option.server.clone().unwrap(), option.data_port);
-    let dst_addr:SocketAddr = mapping.forward.parse().unwrap();
-
-    let meta_msg:String = format!("data:{}:{}", client, bind_id);
-    
-    tokio::spawn(async move { 
-        log::debug!("connect to {}", server_data_addr.to_string());
-        let mut tls_fwd_stream = new_tls_stream("localhost", server_data_addr, &ca_file, &cert_file, &key_file).await;
-        log::debug!("connected to {}", server_data_addr.to_string());
-        tls_fwd_stream.write(meta_msg.clone().as_bytes()).await.unwrap();
-        tls_fwd_stream.write(&META_MSG_END_FLAG).await.unwrap();
-        tls_fwd_stream.flush().await.unwrap();
-        log::debug!("connect to app {:?}", dst_addr);
-        let mut dst_stream = TcpStream::connect(dst_addr).await.unwrap();
-        log::debug!("connected to app {:?}", dst_addr);
-        let result = client_data_forward(&mut tls_fwd_stream, &mut dst_stream).await;
-        match result {
-            Ok(size) => {
-                log::info!("proccess tx[{}] success", bind_id)
-            },
-            Err(e) => {
-                log::error!("proccess tx[{}] error: {}", bind_id, e)
+
+    let handshake = async {
+        let mut tls_stream = client_tls_handshake(&state.metrics, &option.server_tls_name(), socket, &ca_file, &cert_file, &key_file).await?;
+        let purpose = proto::read_meta(&mut tls_stream).await?;
+        Ok::<_, std::io::Error>((tls_stream, purpose))
+    };
+    let (mut tls_stream, purpose) = match timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), handshake).await {
+        Ok(Ok(conn)) => conn,
+        Ok(Err(e)) => {
+            log::error!("server connection handshake error: {}", e);
+            return;
+        },
+        Err(_) => {
+            log::error!("server connection handshake timeout");
+            return;
+        }
+    };
+
+    match purpose.as_str() {
+        "main" => {
+            let id = generate_uuid();
+            *client_id.lock().unwrap() = id.clone();
+            let meta_msg:String = format!("main:{}:{}:{}", client_name, id, proto::CMD_VERSION);
+            let mut recv_buffer: Vec<u8> = Vec::new();
+            let handshake = async {
+                proto::write_meta(&mut tls_stream, &meta_msg).await?;
+                client_read_version(&mut tls_stream, &mut recv_buffer).await
+            };
+            match handshake.await {
+                Ok(version) => {
+                    log::info!("server connected, protocol version {}", version);
+                    state.connected(peer_addr, version);
+                },
+                Err(e) => {
+                    log::error!("main connection handshake error: {}", e);
+                    return;
+                }
+            }
+            if let Err(e) = client_signal_loop(&option, &mut tls_stream, recv_buffer, &state, reload_rx).await {
+                log::error!("main connection error: {:?}", e);
             }
-        }                       
-    });
-   
-    Ok(())
+            // 已被新的信令连接替换时不更新状态
+            if *client_id.lock().unwrap() == id {
+                state.disconnected();
+            }
+        },
+        "mux" => {
+            let id = client_id.lock().unwrap().clone();
+            let meta_msg:String = format!("mux:{}:{}", client_name, id);
+            if let Err(e) = proto::write_meta(&mut tls_stream, &meta_msg).await {
+                log::error!("mux channel handshake error: {}", e);
+                return;
+            }
+            log::info!("mux channel connected");
+            let (_mux_control, mut incoming) = new_mux_session(tls_stream, MuxMode::Client);
+            while let Some(stream) = incoming.recv().await {
+                tokio::spawn(client_mux_forward(stream, state.clone()));
+            }
+            log::info!("mux channel closed");
+        },
+        "data" => {
+            client_stream_forward(tls_stream, String::from("data connection"), state).await;
+        },
+        _ => {
+            log::error!("unknown server connection type: {}", purpose);
+        }
+    }
 }
 
-async fn client_data_forward(tls_stream:&mut TlsClientStream<TcpStream>, tcp_stream: &mut TcpStream) -> Result<usize, tokio::io::Error>  {
-    let mut tls_recv_buffer:[u8; 1024] = [0; 1024];
-    let mut dst_recv_buffer:[u8; 1024] = [0; 1024];
-    log::trace!("start process data ....");
+/// 处理信令连接, `recv_buffer` 中可能有握手时读到的命令
+async fn client_signal_loop(option: &AppOption, tls_stream: &mut TlsClientStream<TcpStream>, mut recv_buffer: Vec<u8>, state: &ClientState
+    , mut reload_rx: watch::Receiver<AppOption>) -> AppResult<()> {
+    // 转发请求在独立任务中连接目标, 完成后通过该通道回复服务端
+    let (rsp_tx, mut rsp_rx) = mpsc::channel::<proto::ProtoCmd>(CLIENT_RSP_QUEUE_SIZE);
+    let mut heartbeat = proto::Heartbeat::new(option.heartbeat_interval, option.heartbeat_timeout);
+
+    // 向服务端声明客户端自己的映射, 由服务端按策略开始监听. 使用最近一次加载的配置
+    let mut mappings = reload_rx.borrow_and_update().mappings.clone();
+    if !mappings.is_empty() {
+        let conf = proto::ProtoCmdBody::Conf(proto::ClientConf { mappings: mappings.clone() });
+        let reqcmd = proto::ProtoCmd::Request(proto::ProtoCmdRequest::new(conf));
+        proto::write_cmd(tls_stream, &reqcmd).await?;
+    }
+
     loop {
-        select! {
-            fwd_res = tls_stream.read(&mut tls_recv_buffer) => {
-                match fwd_res {
-                    Ok(size) => {
-                        if size > 0 {
-                            log::trace!("send data to app size:{}", size);
-                            tcp_stream.write_all(&tls_recv_buffer[0..size]).await?;
-                            tcp_stream.flush().await?;
-                            log::trace!("sended data to app size:{}", size);
-                        } else {
-                            //log::info!("forward connection closed");
-                            //break;
-                        }
+        let proto_cmd = select! {
+            result = proto::read_cmd(tls_stream, &mut recv_buffer) => {
+                match result {
+                    Ok(cmd) => {
+                        heartbeat.received();
+                        cmd
                     },
                     Err(e) => {
                         let err_kind = e.kind();
                         match err_kind {
                             std::io::ErrorKind::UnexpectedEof => {
-                                log::info!("tls stream  connection closed");
-                                break;
+                                log::info!("server connection closed");
+                                return Ok(());
                             },
                             _ => {
-                                log::error!("read from tls stream error: {}", e);
-                                return Err(e);
+                                log::error!("main error: {}", e);
+                                return Err(e.into());
                             }
                         }
                     }
                 }
-                
             },
+            ping = heartbeat.tick() => {
+                match ping {
+                    Ok(reqcmd) => proto::write_cmd(tls_stream, &reqcmd).await?,
+                    Err(e) => {
+                        log::error!("server heartbeat error: {}", e);
+                        return Err(e.into());
+                    }
+                }
+                continue;
+            },
+            rspcmd = rsp_rx.recv() => {
+                // rsp_tx 一直持有, 不会返回 None
+                if let Some(rspcmd) = rspcmd {
+                    proto::write_cmd(tls_stream, &rspcmd).await?;
+                }
+                continue;
+            },
+            Ok(_) = reload_rx.changed() => {
+                let latest = reload_rx.borrow_and_update().mappings.clone();
+                if latest != mappings {
+                    // 服务端收到新的声明后替换之前的映射, 空列表表示全部移除
+                    log::info!("mappings changed, declare {} mappings to server", latest.len());
+                    let conf = proto::ProtoCmdBody::Conf(proto::ClientConf { mappings: latest.clone() });
+                    let reqcmd = proto::ProtoCmd::Request(proto::ProtoCmdRequest::new(conf));
+                    proto::write_cmd(tls_stream, &reqcmd).await?;
+                    state.set_mappings(latest.clone());
+                    mappings = latest;
+                }
+                continue;
+            }
+        };
+        log::debug!("client read data: {:?}", proto_cmd);
 
-            dst_res = tcp_stream.read(&mut dst_recv_buffer) => {
-                match dst_res {
-                    Ok(size) => {
-                        if size > 0 {
-
-                            log::trace!("send data to forward port size:{}", size);
-                            tls_stream.write_all(&dst_recv_buffer[0..size]).await?;
-                            tls_stream.flush().await?;
-                            log::trace!("sended data to forward port size:{}", size);
-                        } else {
-                            //
-                        }
+        match proto_cmd {
+            proto::ProtoCmd::Request(req) => {
+                let rspcmd = match &req.body {
+                    proto::ProtoCmdBody::Conn(conn) => {
+                        let proto::ProxyRequest{bind_id, client, mapping, token, target} = conn.clone();
+                        let target = target.unwrap_or(mapping.forward.clone());
+                        let rsp = req.response("", String::new());
+                        let session = state.sessions.open(&bind_id, target.clone());
+                        state.sessions.set_route(&bind_id, &mapping.name, &client);
+                        tokio::spawn(client_forward(option.clone(), bind_id, client, token, *mapping, target, rsp, rsp_tx.clone(), session, state.metrics.clone()));
+                        continue;
                     },
-                    Err(e) => {
-                        let err_kind = e.kind();
-                        match err_kind {
-                            std::io::ErrorKind::UnexpectedEof => {
-                                log::info!("tcp stream connection closed");
-                                break;
-                            },
-                            _ => {
-                                log::error!("read from tcp stream error: {}", e);
-                                return Err(e);
-                            }
-                        }
+                    proto::ProtoCmdBody::Ping => proto::pong(&req),
+                    _ => {
+                        log::warn!("unsupported request from server: {:?}", req.kind());
+                        req.unsupported()
                     }
+                };
+                proto::write_cmd(tls_stream, &rspcmd).await?;
+            },
+            proto::ProtoCmd::Response(rsp) => {
+                log::debug!("client recv response: {:?}", rsp);
+                if rsp.cmd_type == proto::CmdKind::Conf {
+                    if rsp.is_ok() {
+                        log::info!("client mappings registered: {}", rsp.message);
+                    } else {
+                        log::error!("client mappings rejected: {}", rsp.message);
+                    }
+                    state.set_mappings_status(format!("{}: {}", rsp.status, rsp.message));
                 }
             }
         }
     }
+}
+
+/// 维持一条到服务端的多路复用连接, 服务端在其上为每个转发会话打开一个流
+async fn client_mux_channel(option: AppOption, client_name: String, client_id: String, state: ClientState) {
+    let ca_file = option.ca_cert.clone().unwrap();
+    let cert_file = option.cert.clone().unwrap();
+    let key_file = option.key.clone().unwrap();
+    let server_signal_addr = option.server_signal_addr().unwrap();
+    let server_name = option.server_tls_name();
 
-    Ok(0)
-}
\ No newline at end of file
+    loop {
+        let connect = async {
+            let socket = tcp_connect(&server_signal_addr, false).await?;
+            let mut tls_stream = client_tls_handshake(&state.metrics, &server_name, socket, &ca_file, &cert_file, &key_file).await?;
+            let meta_msg:String = format!("mux:{}:{}", client_name, client_id);
+            proto::write_meta(&mut tls_stream, &meta_msg).await?;
+            Ok::<_, std::io::Error>(tls_stream)
+        };
+        match connect.await {
+            Ok(tls_stream) => {
+                log::info!("mux channel connected to {}", server_signal_addr);
+                let (_mux_control, mut incoming) = new_mux_session(tls_stream, MuxMode::Client);
+                while let Some(stream) = incoming.recv().await {
+                    tokio::spawn(client_mux_forward(stream, state.clone()));
+                }
+                log::info!("mux channel closed");
+            },
+            Err(e) => {
+                log::error!("mux channel handshake error: {}", e);
+            }
+        }
+
+        sleep(Duration::from_secs(MUX_CHANNEL_RECONNECT_TIMEOUT)).await;
+    }
+}
+
+async fn client_mux_forward(stream: MuxStream, state: ClientState) {
+    let label = format!("mux stream {}", stream.id());
+    client_stream_forward(stream, label, state).await
+}
+
+/// 读取转发请求, 连接目标后回复结果并开始转发
+async fn client_stream_forward<S: AsyncStream>(mut stream: S, label: String, state: ClientState) {
+    let req = match proto::read_cmd_exact(&mut stream).await {
+        Ok(proto::ProtoCmd::Request(req)) => req,
+        Ok(cmd) => {
+            log::error!("{} unexpected cmd: {:?}", label, cmd);
+            return;
+        },
+        Err(e) => {
+            log::error!("{} read request error: {}", label, e);
+            return;
+        }
+    };
+
+    let proto::ProtoCmdBody::Conn(proto::ProxyRequest{bind_id, client, mapping, target, ..}) = req.body.clone() else {
+        log::error!("{} unexpected request: {:?}", label, req.kind());
+        proto::write_cmd(&mut stream, &req.unsupported()).await.unwrap_or(());
+        return;
+    };
+
+    let target = target.unwrap_or(mapping.forward.clone());
+    let session = state.sessions.open(&bind_id, target.clone());
+    state.sessions.set_route(&bind_id, &mapping.name, &client);
+    log::debug!("connect to app {} for tx[{}]", target, bind_id);
+    let dst_stream = client_connect_target(&mapping, &target).await;
+
+    let (status, message) = match &dst_stream {
+        Ok(_) => (proto::STATUS_OK, String::from("proccess success")),
+        Err(e) => (proto::error_status(e), format!("connect to {} error: {}", target, e)),
+    };
+    let rspcmd = proto::ProtoCmd::Response(req.response(status, message));
+    if let Err(e) = proto::write_cmd(&mut stream, &rspcmd).await {
+        log::error!("proccess tx[{}] response error: {}", bind_id, e);
+        return;
+    }
+
+    let Ok(dst_stream) = dst_stream else {
+        log::error!("proccess tx[{}] connect to app {} failed", bind_id, target);
+        return;
+    };
+    log::debug!("connected to app {:?}", target);
+    client_relay_target(&mut stream, dst_stream, &bind_id, &session).await;
+}
+
+/// 客户端连接的目标, UDP 映射的数据报在转发流上按长度分隔
+enum ForwardTarget {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+async fn client_connect_target(mapping: &MappingConfig, target: &str) -> std::io::Result<ForwardTarget> {
+    if mapping.is_udp() {
+        proxy::connect_udp(target).await.map(ForwardTarget::Udp)
+    } else {
+        tcp_connect(target, true).await.map(ForwardTarget::Tcp)
+    }
+}
+
+/// 在服务端一侧的流上统计字节数, 会话被关闭时结束转发
+async fn client_relay_target<S: AsyncStream>(stream: &mut S, dst: ForwardTarget, bind_id: &str, session: &SessionGuard) {
+    let mut stream = CountedStream::new(stream, session.counter());
+    let relay = async {
+        match dst {
+            ForwardTarget::Tcp(mut dst_stream) => stream_forward(&mut stream, &mut dst_stream).await.map(|_| ()),
+            ForwardTarget::Udp(socket) => proxy::udp_stream_relay(&mut stream, socket).await,
+        }
+    };
+    let result = select! {
+        result = relay => result,
+        _ = session.closed() => {
+            log::info!("proccess tx[{}] closed by admin", bind_id);
+            return;
+        }
+    };
+    match result {
+        Ok(_) => {
+            log::info!("proccess tx[{}] success", bind_id)
+        },
+        Err(e) => {
+            log::error!("proccess tx[{}] error: {}", bind_id, e)
+        }
+    }
+}
+
+/// 通过数据端口转发: 先连接目标并回复结果, 成功后再建立到服务端的数据连接
+#[allow(clippy::too_many_arguments)]
+async fn client_forward(option: AppOption, bind_id:String, client:String, token: String, mapping: MappingConfig, target: String
+    , mut rsp: proto::ProtoCmdResponse, rsp_tx: mpsc::Sender<proto::ProtoCmd>, session: SessionGuard, metrics: Metrics) {
+    let ca_file = option.ca_cert.clone().unwrap();
+    let cert_file = option.cert.clone().unwrap();
+    let key_file = option.key.clone().unwrap();
+
+    let server_data_addr = option.server_data_addr().unwrap();
+
+    let meta_msg:String = format!("data:{}:{}:{}", client, bind_id, token);
+
+    log::debug!("connect to app {:?}", target);
+    let dst_stream = client_connect_target(&mapping, &target).await;
+    (rsp.status, rsp.message) = match &dst_stream {
+        Ok(_) => (String::from(proto::STATUS_OK), String::from("proccess success")),
+        Err(e) => (String::from(proto::error_status(e)), format!("connect to {} error: {}", target, e)),
+    };
+    rsp_tx.send(proto::ProtoCmd::Response(rsp)).await.unwrap_or(());
+    let dst_stream = match dst_stream {
+        Ok(dst_stream) => dst_stream,
+        Err(e) => {
+            log::error!("proccess tx[{}] connect to app {} error: {}", bind_id, target, e);
+            return;
+        }
+    };
+    log::debug!("connected to app {:?}", target);
+
+    log::debug!("connect to {}", server_data_addr);
+    let connect = async {
+        let socket = tcp_connect(&server_data_addr, false).await?;
+        let mut tls_fwd_stream = client_tls_handshake(&metrics, &option.server_tls_name(), socket, &ca_file, &cert_file, &key_file).await?;
+        proto::write_meta(&mut tls_fwd_stream, &meta_msg).await?;
+        Ok::<_, std::io::Error>(tls_fwd_stream)
+    };
+    let mut tls_fwd_stream = match connect.await {
+        Ok(tls_fwd_stream) => tls_fwd_stream,
+        Err(e) => {
+            log::error!("proccess tx[{}] handshake error: {}", bind_id, e);
+            return;
+        }
+    };
+    log::debug!("connected to {}", server_data_addr);
+    client_relay_target(&mut tls_fwd_stream, dst_stream, &bind_id, &session).await;
+}
@@ -26,10 +26,7 @@ where T : AsyncRead + AsyncWrite + Unpin {
     }
 
     pub fn is_weberror(&self) -> bool {
-        match self {
-            AppError::WebError(_) => true,
-            _ => false,
-        }
+        matches!(self, AppError::WebError(_))
     }
     pub fn to_type<B>(self) -> AppError<B> 
     where B : AsyncRead + AsyncWrite + Unpin{
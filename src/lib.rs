@@ -4,15 +4,17 @@ mod error;
 mod mappings;
 mod utils;
 mod proto;
+mod mux;
+mod proxy;
 mod server;
 mod client;
+mod admin;
+mod reload;
 
-pub use error::{AppResult, AppError};
+pub use error::{AppResult, AppTypeResult, AppError};
 pub use option::{AppOption, Builder};
 pub use app::App;
-pub use mappings::MappingConfig;
+pub use mappings::{ClientPolicy, MappingConfig};
 pub use utils::*;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate log;
@@ -1,26 +1,18 @@
 use natproxy::{AppOption, AppResult, App};
-use std::env;
-use dotenvy;
 use log::LevelFilter;
 use lite_log::LiteLogger;
 
 async fn run_main() -> AppResult<()> {
     let option = AppOption::parse_env()?;  
 
-    let log_level = match option.log_level.as_str() {
-        "trace" => LevelFilter::Trace,
-        "debug" => LevelFilter::Debug,
-        "info" => LevelFilter::Info,
-        "warn" => LevelFilter::Warn,
-        "error" => LevelFilter::Error,
-        _ => LevelFilter::Info
-    };
-
-    let logger = LiteLogger::new()
+    // 模块级别不做限制, 由全局级别控制, 重新加载配置时可以修改
+    LiteLogger::new()
         .with_level(LevelFilter::Off)
         .with_local_timestamps()
-        .with_module_level("natproxy", log_level)
-        .init();
+        .with_module_level("natproxy", LevelFilter::Trace)
+        .init()
+        .unwrap_or(());
+    log::set_max_level(option.log_level_filter());
 
     let mut app = App::new(option);
     app.start().await?;
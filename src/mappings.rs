@@ -1,7 +1,9 @@
-use std::{net::SocketAddr, vec};
+use std::{net::SocketAddr, ops::RangeInclusive, vec};
 
 use serde::{Deserialize, Serialize};
 
+use crate::proxy::HeaderRules;
+
 fn default_forward() -> String {
     "".to_string()
 }
@@ -20,6 +22,12 @@ pub struct MappingConfig {
     pub listen: Option<SocketAddr>,
     #[serde(default = "default_forward")]
     pub forward: String,
+    /// httpreverse 按 Host, https 按 SNI 匹配的域名, 为空时作为同端口的默认映射
+    #[serde(default)]
+    pub domain: String,
+    /// httpreverse 匹配的路径前缀, 为空时匹配所有路径
+    #[serde(default)]
+    pub path: String,
     #[serde(default = "default_header")]
     pub headers: Vec<Vec<String>>,
 }
@@ -32,6 +40,8 @@ impl MappingConfig {
             client,
             listen: None,
             forward,
+            domain: String::new(),
+            path: String::new(),
             headers,
         }
     }
@@ -44,7 +54,133 @@ impl MappingConfig {
         self.mode.eq_ignore_ascii_case("https")
     }
 
+    pub fn is_httpreverse(&self) -> bool {
+        self.mode.eq_ignore_ascii_case("httpreverse")
+    }
+
+    /// 是否可以与其它映射共享监听地址, 按域名区分
+    pub fn is_virtual_host(&self) -> bool {
+        self.is_httpreverse() || self.is_https()
+    }
+
+    pub fn is_socks5(&self) -> bool {
+        self.mode.eq_ignore_ascii_case("socks5")
+    }
+
+    pub fn is_udp(&self) -> bool {
+        self.mode.eq_ignore_ascii_case("udp")
+    }
+
     pub fn is_tcp(&self) -> bool {
         self.mode.eq_ignore_ascii_case("tcp")
     }
-}
\ No newline at end of file
+
+    pub fn header_rules(&self) -> Result<HeaderRules, String> {
+        HeaderRules::parse(&self.headers)
+    }
+
+    /// 检查映射配置, 错误信息包含映射名称
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.headers.is_empty() && !self.is_http() && !self.is_httpreverse() {
+            return Err(format!("mapping {}: headers are only supported by http and httpreverse mode", self.name));
+        }
+        if self.is_https() && !self.path.is_empty() {
+            return Err(format!("mapping {}: path is not supported by https mode", self.name));
+        }
+        self.header_rules().map_err(|e| format!("mapping {}: {}", self.name, e))?;
+        Ok(())
+    }
+}
+/// 服务端允许客户端自行声明映射的策略
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientPolicy {
+    /// 客户端名称, `*` 匹配所有客户端
+    pub client: String,
+    /// 允许监听的端口或端口范围, 如 `9000`, `9000-9100`
+    pub ports: Vec<String>,
+    /// 允许的映射模式, 为空时允许 proxy_on 中启用的所有模式
+    #[serde(default)]
+    pub modes: Vec<String>,
+}
+
+impl ClientPolicy {
+    fn port_ranges(&self) -> Result<Vec<RangeInclusive<u16>>, String> {
+        self.ports.iter().map(|x| {
+            let (start, end) = x.split_once('-').unwrap_or((x, x));
+            match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+                (Ok(start), Ok(end)) if start <= end => Ok(start..=end),
+                _ => Err(format!("client policy {}: invalid port range {:?}", self.client, x)),
+            }
+        }).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.port_ranges().map(|_| ())
+    }
+
+    /// 策略是否允许客户端声明该映射
+    pub fn allows(&self, client: &str, mapping: &MappingConfig) -> bool {
+        if self.client != "*" && self.client != client {
+            return false;
+        }
+        if !self.modes.is_empty() && !self.modes.iter().any(|x| x.eq_ignore_ascii_case(&mapping.mode)) {
+            return false;
+        }
+        let Some(listen) = mapping.listen else {
+            return false;
+        };
+        self.port_ranges().unwrap_or_default().iter().any(|x| x.contains(&listen.port()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(client: &str, ports: &[&str], modes: &[&str]) -> ClientPolicy {
+        ClientPolicy {
+            client: client.to_string(),
+            ports: ports.iter().map(|x| x.to_string()).collect(),
+            modes: modes.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    fn mapping(mode: &str, listen: Option<&str>) -> MappingConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": "declared",
+            "mode": mode,
+            "listen": listen,
+            "forward": "127.0.0.1:80",
+        })).unwrap()
+    }
+
+    #[test]
+    fn parse_port_ranges() {
+        assert_eq!(policy("*", &["9000", "9100-9200", " 80 - 81 "], &[]).port_ranges().unwrap(), vec![9000..=9000, 9100..=9200, 80..=81]);
+        assert!(policy("*", &[], &[]).validate().is_ok());
+        for ports in ["9200-9100", "abc", "-9000", "9000-", "70000", "1-2-3"] {
+            assert!(policy("*", &[ports], &[]).validate().is_err(), "{}", ports);
+        }
+    }
+
+    #[test]
+    fn allows_by_client_port_and_mode() {
+        let tcp = mapping("tcp", Some("0.0.0.0:9050"));
+        let rule = policy("client1", &["9000-9100"], &[]);
+        assert!(rule.allows("client1", &tcp));
+        assert!(!rule.allows("client2", &tcp));
+        assert!(!rule.allows("client1", &mapping("tcp", Some("0.0.0.0:9101"))));
+        assert!(!rule.allows("client1", &mapping("tcp", None)));
+
+        assert!(policy("*", &["9050"], &[]).allows("anyone", &tcp));
+
+        let rule = policy("*", &["9000-9100"], &["UDP", "socks5"]);
+        assert!(!rule.allows("client1", &tcp));
+        assert!(rule.allows("client1", &mapping("udp", Some("0.0.0.0:9050"))));
+    }
+
+    #[test]
+    fn invalid_ports_allow_nothing() {
+        assert!(!policy("*", &["bad"], &[]).allows("client1", &mapping("tcp", Some("0.0.0.0:9050"))));
+    }
+}
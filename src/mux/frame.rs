@@ -0,0 +1,158 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const MUX_VERSION: u8 = 0;
+/// version(1) + type(1) + flags(2) + stream_id(4) + length(4)
+pub const MUX_HEADER_SIZE: usize = 12;
+/// 每个流的初始接收窗口
+pub const INITIAL_WINDOW_SIZE: u32 = 256 * 1024;
+/// 单个数据帧的最大负载
+pub const MAX_DATA_FRAME_SIZE: usize = 16 * 1024;
+
+pub const FLAG_SYN: u16 = 0x1;
+pub const FLAG_ACK: u16 = 0x2;
+pub const FLAG_FIN: u16 = 0x4;
+pub const FLAG_RST: u16 = 0x8;
+
+/// GoAway 错误码
+pub const GO_AWAY_NORMAL: u32 = 0;
+pub const GO_AWAY_PROTOCOL_ERROR: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MuxFrameType {
+    Data,
+    WindowUpdate,
+    Ping,
+    GoAway,
+}
+
+impl MuxFrameType {
+    fn as_u8(&self) -> u8 {
+        match self {
+            MuxFrameType::Data => 0x0,
+            MuxFrameType::WindowUpdate => 0x1,
+            MuxFrameType::Ping => 0x2,
+            MuxFrameType::GoAway => 0x3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<MuxFrameType> {
+        match value {
+            0x0 => Some(MuxFrameType::Data),
+            0x1 => Some(MuxFrameType::WindowUpdate),
+            0x2 => Some(MuxFrameType::Ping),
+            0x3 => Some(MuxFrameType::GoAway),
+            _ => None,
+        }
+    }
+}
+
+/// 多路复用帧.
+///
+/// `Data` 帧的 `length` 为负载长度; `WindowUpdate` 为窗口增量;
+/// `Ping` 为透传值; `GoAway` 为错误码.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MuxFrame {
+    pub frame_type: MuxFrameType,
+    pub flags: u16,
+    pub stream_id: u32,
+    pub length: u32,
+    pub payload: Vec<u8>,
+}
+
+impl MuxFrame {
+    pub fn data(stream_id: u32, flags: u16, payload: Vec<u8>) -> Self {
+        Self { frame_type: MuxFrameType::Data, flags, stream_id, length: payload.len() as u32, payload }
+    }
+
+    pub fn window_update(stream_id: u32, flags: u16, delta: u32) -> Self {
+        Self { frame_type: MuxFrameType::WindowUpdate, flags, stream_id, length: delta, payload: vec![] }
+    }
+
+    pub fn ping(flags: u16, opaque: u32) -> Self {
+        Self { frame_type: MuxFrameType::Ping, flags, stream_id: 0, length: opaque, payload: vec![] }
+    }
+
+    pub fn go_away(code: u32) -> Self {
+        Self { frame_type: MuxFrameType::GoAway, flags: 0, stream_id: 0, length: code, payload: vec![] }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MUX_HEADER_SIZE + self.payload.len());
+        buf.push(MUX_VERSION);
+        buf.push(self.frame_type.as_u8());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.extend_from_slice(&self.stream_id.to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+/// 读取一个多路复用帧, 仅在会话读任务中使用, 不要求取消安全
+pub async fn read_mux_frame<R>(reader: &mut R) -> io::Result<MuxFrame>
+where R: AsyncRead + Unpin {
+    let mut header = [0u8; MUX_HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    if header[0] != MUX_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported mux version {}", header[0])));
+    }
+
+    let frame_type = MuxFrameType::from_u8(header[1])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown mux frame type {}", header[1])))?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let stream_id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+    let mut payload = vec![];
+    if frame_type == MuxFrameType::Data && length > 0 {
+        if length > INITIAL_WINDOW_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("mux data frame too large: {}", length)));
+        }
+        payload = vec![0u8; length as usize];
+        reader.read_exact(&mut payload).await?;
+    }
+
+    Ok(MuxFrame { frame_type, flags, stream_id, length, payload })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_round_trip() {
+        let frames = [
+            MuxFrame::data(1, FLAG_SYN, b"payload".to_vec()),
+            MuxFrame::window_update(2, FLAG_ACK | FLAG_FIN, 4096),
+            MuxFrame::ping(FLAG_SYN, 7),
+            MuxFrame::go_away(GO_AWAY_PROTOCOL_ERROR),
+        ];
+        let data: Vec<u8> = frames.iter().flat_map(|x| x.encode()).collect();
+        let mut reader = data.as_slice();
+        for frame in frames {
+            assert_eq!(read_mux_frame(&mut reader).await.unwrap(), frame);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_data_frame_is_rejected() {
+        let mut data = MuxFrame::data(1, 0, vec![]).encode();
+        data[8..MUX_HEADER_SIZE].copy_from_slice(&(INITIAL_WINDOW_SIZE + 1).to_be_bytes());
+        let err = read_mux_frame(&mut data.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn unknown_version_and_type_are_rejected() {
+        let mut data = MuxFrame::ping(0, 0).encode();
+        data[0] = MUX_VERSION + 1;
+        assert!(read_mux_frame(&mut data.as_slice()).await.is_err());
+
+        let mut data = MuxFrame::ping(0, 0).encode();
+        data[1] = 0x7f;
+        assert!(read_mux_frame(&mut data.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,10 @@
+mod frame;
+mod session;
+mod stream;
+
+pub use session::{
+    new_mux_session,
+    MuxControl,
+    MuxMode,
+};
+pub use stream::MuxStream;
@@ -0,0 +1,380 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+
+use super::frame::{
+    read_mux_frame, MuxFrame, MuxFrameType, FLAG_ACK, FLAG_FIN, FLAG_RST, FLAG_SYN, GO_AWAY_NORMAL, GO_AWAY_PROTOCOL_ERROR,
+};
+use super::stream::{MuxStream, StreamState};
+
+const INCOMING_STREAM_BACKLOG: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MuxMode {
+    /// 主动发起连接的一方, 使用奇数流ID
+    Client,
+    /// 接受连接的一方, 使用偶数流ID
+    Server,
+}
+
+pub(crate) struct SessionInner {
+    streams: Mutex<HashMap<u32, Arc<Mutex<StreamState>>>>,
+    frame_tx: mpsc::UnboundedSender<MuxFrame>,
+    next_id: AtomicU32,
+    mode: MuxMode,
+    closed: AtomicBool,
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl SessionInner {
+    /// 发送帧到写任务, 会话已关闭时返回 false
+    pub(crate) fn send_frame(&self, frame: MuxFrame) -> bool {
+        if self.closed.load(Ordering::Acquire) {
+            return false;
+        }
+        self.frame_tx.send(frame).is_ok()
+    }
+
+    pub(crate) fn remove_stream(&self, id: u32) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    fn close(&self) {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            self.shutdown_tx.send(true).unwrap_or(());
+            let streams: Vec<_> = self.streams.lock().unwrap().drain().map(|(_, s)| s).collect();
+            for stream in streams {
+                stream.lock().unwrap().on_reset();
+            }
+        }
+    }
+}
+
+/// 多路复用会话的控制句柄, 可克隆, 用于打开新流和关闭会话
+#[derive(Clone)]
+pub struct MuxControl {
+    inner: Arc<SessionInner>,
+}
+
+impl MuxControl {
+    pub fn open_stream(&self) -> io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "mux session closed"));
+        }
+
+        let id = self.inner.next_id.fetch_add(2, Ordering::AcqRel);
+        let state = Arc::new(Mutex::new(StreamState::new()));
+        self.inner.streams.lock().unwrap().insert(id, state.clone());
+        if !self.inner.send_frame(MuxFrame::window_update(id, FLAG_SYN, 0)) {
+            self.inner.remove_stream(id);
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "mux session closed"));
+        }
+        Ok(MuxStream::new(id, state, self.inner.clone()))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::Acquire)
+    }
+
+    pub fn close(&self) {
+        self.inner.send_frame(MuxFrame::go_away(GO_AWAY_NORMAL));
+        self.inner.close();
+    }
+}
+
+/// 在 `io` 上建立多路复用会话, 返回控制句柄和对端打开的流
+pub fn new_mux_session<T>(io: T, mode: MuxMode) -> (MuxControl, mpsc::Receiver<MuxStream>)
+where T: AsyncRead + AsyncWrite + Send + 'static {
+    let (frame_tx, frame_rx) = mpsc::unbounded_channel::<MuxFrame>();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (incoming_tx, incoming_rx) = mpsc::channel::<MuxStream>(INCOMING_STREAM_BACKLOG);
+    let inner = Arc::new(SessionInner {
+        streams: Mutex::new(HashMap::new()),
+        frame_tx,
+        next_id: AtomicU32::new(if mode == MuxMode::Client { 1 } else { 2 }),
+        mode,
+        closed: AtomicBool::new(false),
+        shutdown_tx,
+    });
+
+    let (reader, writer) = tokio::io::split(io);
+    tokio::spawn(mux_write_loop(writer, frame_rx, shutdown_rx.clone()));
+
+    let read_inner = inner.clone();
+    tokio::spawn(async move {
+        let mut shutdown_rx = shutdown_rx;
+        select! {
+            result = mux_read_loop(reader, read_inner.clone(), incoming_tx) => {
+                if let Err(e) = result {
+                    log::debug!("mux session read error: {}", e);
+                }
+            },
+            _ = shutdown_rx.changed() => {},
+        }
+        read_inner.close();
+        log::debug!("mux session closed");
+    });
+
+    (MuxControl { inner }, incoming_rx)
+}
+
+async fn mux_write_loop<W>(mut writer: W, mut frame_rx: mpsc::UnboundedReceiver<MuxFrame>, mut shutdown_rx: watch::Receiver<bool>)
+where W: AsyncWrite + Unpin {
+    loop {
+        select! {
+            frame = frame_rx.recv() => {
+                let Some(frame) = frame else {
+                    break;
+                };
+                if let Err(e) = writer.write_all(&frame.encode()).await {
+                    log::debug!("mux session write error: {}", e);
+                    break;
+                }
+                // 尽量合并同一批待发送的帧后再 flush
+                while let Ok(frame) = frame_rx.try_recv() {
+                    if writer.write_all(&frame.encode()).await.is_err() {
+                        return;
+                    }
+                }
+                if writer.flush().await.is_err() {
+                    break;
+                }
+            },
+            _ = shutdown_rx.changed() => {
+                while let Ok(frame) = frame_rx.try_recv() {
+                    if writer.write_all(&frame.encode()).await.is_err() {
+                        break;
+                    }
+                }
+                writer.shutdown().await.unwrap_or(());
+                break;
+            }
+        }
+    }
+}
+
+async fn mux_read_loop<R>(mut reader: R, inner: Arc<SessionInner>, incoming_tx: mpsc::Sender<MuxStream>) -> io::Result<()>
+where R: AsyncRead + Unpin {
+    loop {
+        let frame = read_mux_frame(&mut reader).await?;
+        match frame.frame_type {
+            MuxFrameType::Data | MuxFrameType::WindowUpdate => {
+                let state = if frame.flags & FLAG_SYN != 0 {
+                    // 对端只能使用与本端奇偶性相反的流ID
+                    let remote_odd = inner.mode == MuxMode::Server;
+                    if frame.stream_id == 0 || (frame.stream_id % 2 == 1) != remote_odd {
+                        inner.send_frame(MuxFrame::go_away(GO_AWAY_PROTOCOL_ERROR));
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("mux stream id {} from wrong side", frame.stream_id)));
+                    }
+                    let state = Arc::new(Mutex::new(StreamState::new()));
+                    let duplicate = match inner.streams.lock().unwrap().entry(frame.stream_id) {
+                        Entry::Occupied(_) => true,
+                        Entry::Vacant(entry) => {
+                            entry.insert(state.clone());
+                            false
+                        }
+                    };
+                    if duplicate {
+                        // 不能替换正在使用的流
+                        log::debug!("mux session reject duplicate stream {}", frame.stream_id);
+                        inner.send_frame(MuxFrame::window_update(frame.stream_id, FLAG_RST, 0));
+                        continue;
+                    }
+                    let stream = MuxStream::new(frame.stream_id, state.clone(), inner.clone());
+                    if incoming_tx.try_send(stream).is_err() {
+                        // 没有人接收新流, 丢弃时会自动回复 RST
+                        log::debug!("mux session reject incoming stream {}", frame.stream_id);
+                        continue;
+                    }
+                    inner.send_frame(MuxFrame::window_update(frame.stream_id, FLAG_ACK, 0));
+                    Some(state)
+                } else {
+                    inner.streams.lock().unwrap().get(&frame.stream_id).cloned()
+                };
+
+                let Some(state) = state else {
+                    if frame.flags & FLAG_RST == 0 {
+                        log::trace!("mux frame for unknown stream {}", frame.stream_id);
+                    }
+                    continue;
+                };
+
+                let mut state = state.lock().unwrap();
+                if frame.frame_type == MuxFrameType::Data {
+                    if !state.on_data(&frame.payload) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "mux receive window exceeded"));
+                    }
+                } else {
+                    state.on_window_update(frame.length);
+                }
+                if frame.flags & FLAG_FIN != 0 {
+                    state.on_fin();
+                }
+                if frame.flags & FLAG_RST != 0 {
+                    state.on_reset();
+                }
+            },
+            MuxFrameType::Ping => {
+                if frame.flags & FLAG_SYN != 0 {
+                    inner.send_frame(MuxFrame::ping(FLAG_ACK, frame.length));
+                }
+            },
+            MuxFrameType::GoAway => {
+                log::debug!("mux session recv go away: {}", frame.length);
+                return Ok(());
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::frame::{INITIAL_WINDOW_SIZE, MUX_HEADER_SIZE};
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::time::{sleep, timeout, Duration};
+
+    fn session_pair() -> ((MuxControl, mpsc::Receiver<MuxStream>), (MuxControl, mpsc::Receiver<MuxStream>)) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        (new_mux_session(a, MuxMode::Client), new_mux_session(b, MuxMode::Server))
+    }
+
+    /// 一端为会话, 另一端直接读写帧, 用于构造异常的对端
+    fn raw_peer(mode: MuxMode) -> (DuplexStream, MuxControl, mpsc::Receiver<MuxStream>) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (control, incoming) = new_mux_session(b, mode);
+        (a, control, incoming)
+    }
+
+    async fn read_frame(raw: &mut DuplexStream) -> MuxFrame {
+        timeout(Duration::from_secs(1), read_mux_frame(raw)).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn open_and_accept() {
+        let ((client, _), (_, mut incoming)) = session_pair();
+        let mut local = client.open_stream().unwrap();
+        let local_second = client.open_stream().unwrap();
+        assert_eq!(local.id(), 1);
+        assert_eq!(local_second.id(), 3);
+
+        local.write_all(b"hello").await.unwrap();
+        let mut remote = incoming.recv().await.unwrap();
+        assert_eq!(remote.id(), 1);
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        remote.write_all(b"world").await.unwrap();
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn fin_half_close() {
+        let ((client, _), (_, mut incoming)) = session_pair();
+        let mut local = client.open_stream().unwrap();
+        local.write_all(b"request").await.unwrap();
+        local.shutdown().await.unwrap();
+        assert!(local.write_all(b"more").await.is_err());
+
+        let mut remote = incoming.recv().await.unwrap();
+        let mut data = vec![];
+        remote.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"request");
+
+        // 对端关闭写方向后仍然可以回复
+        remote.write_all(b"response").await.unwrap();
+        remote.shutdown().await.unwrap();
+        let mut data = vec![];
+        local.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"response");
+    }
+
+    #[tokio::test]
+    async fn drop_resets_stream() {
+        let ((client, _), (_, mut incoming)) = session_pair();
+        let mut local = client.open_stream().unwrap();
+        let remote = incoming.recv().await.unwrap();
+        drop(remote);
+
+        let mut buf = [0u8; 1];
+        let err = timeout(Duration::from_secs(1), local.read(&mut buf)).await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(local.write_all(b"x").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn window_exhaustion_and_update() {
+        let ((client, _), (_, mut incoming)) = session_pair();
+        let mut local = client.open_stream().unwrap();
+        let total = INITIAL_WINDOW_SIZE as usize * 2;
+        let writer = tokio::spawn(async move {
+            local.write_all(&vec![7u8; total]).await.unwrap();
+            local
+        });
+
+        // 对端不读取时发送方停在窗口耗尽处
+        let mut remote = incoming.recv().await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert!(!writer.is_finished());
+
+        let mut data = vec![0u8; total];
+        timeout(Duration::from_secs(5), remote.read_exact(&mut data)).await.unwrap().unwrap();
+        assert!(data.iter().all(|x| *x == 7));
+        timeout(Duration::from_secs(1), writer).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_closes_session() {
+        let (mut raw, control, _incoming) = raw_peer(MuxMode::Server);
+        let mut header = MuxFrame::data(1, FLAG_SYN, vec![]).encode();
+        header[8..MUX_HEADER_SIZE].copy_from_slice(&(INITIAL_WINDOW_SIZE + 1).to_be_bytes());
+        raw.write_all(&header).await.unwrap();
+
+        timeout(Duration::from_secs(1), async {
+            while !control.is_closed() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert!(control.open_stream().is_err());
+    }
+
+    #[tokio::test]
+    async fn duplicate_syn_is_reset() {
+        let (mut raw, _control, mut incoming) = raw_peer(MuxMode::Server);
+        raw.write_all(&MuxFrame::window_update(1, FLAG_SYN, 0).encode()).await.unwrap();
+        let ack = read_frame(&mut raw).await;
+        assert_eq!((ack.stream_id, ack.flags), (1, FLAG_ACK));
+        let mut stream = incoming.recv().await.unwrap();
+
+        raw.write_all(&MuxFrame::window_update(1, FLAG_SYN, 0).encode()).await.unwrap();
+        let rst = read_frame(&mut raw).await;
+        assert_eq!((rst.stream_id, rst.flags), (1, FLAG_RST));
+
+        // 原有的流不受影响
+        raw.write_all(&MuxFrame::data(1, 0, b"ok".to_vec()).encode()).await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+
+    #[tokio::test]
+    async fn wrong_parity_syn_goes_away() {
+        let (mut raw, control, _incoming) = raw_peer(MuxMode::Server);
+        raw.write_all(&MuxFrame::window_update(2, FLAG_SYN, 0).encode()).await.unwrap();
+        let frame = read_frame(&mut raw).await;
+        assert_eq!(frame.frame_type, MuxFrameType::GoAway);
+        assert_eq!(frame.length, GO_AWAY_PROTOCOL_ERROR);
+
+        let mut rest = vec![];
+        timeout(Duration::from_secs(1), raw.read_to_end(&mut rest)).await.unwrap().unwrap();
+        assert!(control.is_closed());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::frame::{MuxFrame, FLAG_FIN, FLAG_RST, INITIAL_WINDOW_SIZE, MAX_DATA_FRAME_SIZE};
+use super::session::SessionInner;
+
+pub(crate) struct StreamState {
+    recv_buf: VecDeque<u8>,
+    /// 已被读取但尚未通过 WindowUpdate 归还给对端的字节数
+    consumed: u32,
+    /// 对端剩余可发送的字节数
+    recv_window: u32,
+    send_window: u32,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    remote_closed: bool,
+    local_closed: bool,
+    reset: bool,
+}
+
+impl StreamState {
+    pub(crate) fn new() -> Self {
+        Self {
+            recv_buf: VecDeque::new(),
+            consumed: 0,
+            recv_window: INITIAL_WINDOW_SIZE,
+            send_window: INITIAL_WINDOW_SIZE,
+            read_waker: None,
+            write_waker: None,
+            remote_closed: false,
+            local_closed: false,
+            reset: false,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// 收到数据, 超出接收窗口视为协议错误
+    pub(crate) fn on_data(&mut self, data: &[u8]) -> bool {
+        if data.len() as u32 > self.recv_window {
+            return false;
+        }
+        self.recv_window -= data.len() as u32;
+        self.recv_buf.extend(data);
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    pub(crate) fn on_window_update(&mut self, delta: u32) {
+        self.send_window = self.send_window.saturating_add(delta);
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn on_fin(&mut self) {
+        self.remote_closed = true;
+        self.wake();
+    }
+
+    pub(crate) fn on_reset(&mut self) {
+        self.reset = true;
+        self.wake();
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.reset || (self.local_closed && self.remote_closed)
+    }
+}
+
+/// 多路复用会话上的一个逻辑流
+pub struct MuxStream {
+    id: u32,
+    state: Arc<Mutex<StreamState>>,
+    session: Arc<SessionInner>,
+}
+
+impl MuxStream {
+    pub(crate) fn new(id: u32, state: Arc<Mutex<StreamState>>, session: Arc<SessionInner>) -> Self {
+        Self { id, state, session }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.recv_buf.is_empty() {
+            let size = state.recv_buf.len().min(buf.remaining());
+            let (front, back) = state.recv_buf.as_slices();
+            if size <= front.len() {
+                buf.put_slice(&front[..size]);
+            } else {
+                buf.put_slice(front);
+                buf.put_slice(&back[..size - front.len()]);
+            }
+            state.recv_buf.drain(..size);
+
+            state.consumed += size as u32;
+            if state.consumed >= INITIAL_WINDOW_SIZE / 2 && !state.remote_closed && !state.reset {
+                let delta = state.consumed;
+                state.consumed = 0;
+                state.recv_window += delta;
+                self.session.send_frame(MuxFrame::window_update(self.id, 0, delta));
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if state.reset {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "mux stream reset")));
+        }
+        if state.remote_closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if state.reset {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "mux stream reset")));
+        }
+        if state.local_closed {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "mux stream closed")));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let size = buf.len().min(state.send_window as usize).min(MAX_DATA_FRAME_SIZE);
+        state.send_window -= size as u32;
+        if !self.session.send_frame(MuxFrame::data(self.id, 0, buf[..size].to_vec())) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed")));
+        }
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.local_closed && !state.reset {
+            state.local_closed = true;
+            self.session.send_frame(MuxFrame::window_update(self.id, FLAG_FIN, 0));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let closed = {
+            let mut state = self.state.lock().unwrap();
+            let closed = state.is_closed();
+            state.local_closed = true;
+            closed
+        };
+        if !closed {
+            self.session.send_frame(MuxFrame::window_update(self.id, FLAG_RST, 0));
+        }
+        self.session.remove_stream(self.id);
+    }
+}
@@ -1,23 +1,29 @@
 use std::{
     fs::File,
-    io::{self, BufReader, Read},
+    io::{self, Read},
     net::{IpAddr, SocketAddr},
-    sync::Arc,
     env,
 };
 
 use commander::Commander;
-use rustls::{Certificate, PrivateKey};
+use log::LevelFilter;
 
 use serde::{Deserialize, Serialize};
-use tokio_rustls::{rustls, TlsAcceptor};
 
-use crate::{MappingConfig, AppError, AppResult, mappings};
+use crate::{ClientPolicy, MappingConfig, AppResult};
+use crate::utils::{join_host_port, split_host_port, IpFamily};
 
 
 pub struct Builder {
     inner: AppResult<AppOption>,
 }
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Builder {
     #[inline]
     pub fn new() -> Builder {
@@ -34,6 +40,20 @@ impl Builder {
     }
 
 
+    pub fn connect_mode(self, mode: String) -> Builder {
+        self.and_then(|mut option| {
+            option.connect_mode = mode;
+            Ok(option)
+        })
+    }
+
+    pub fn client_addrs(self, addrs: Vec<String>) -> Builder {
+        self.and_then(|mut option| {
+            option.client_addrs = addrs;
+            Ok(option)
+        })
+    }
+
     pub fn listen_addr(self, addr: IpAddr) -> Builder {
         self.and_then(|mut option| {
             option.listen = addr;
@@ -42,13 +62,111 @@ impl Builder {
     }
 
 
-    pub fn server(self, addr: Option<IpAddr>) -> Builder {
+    pub fn server(self, addr: Option<String>) -> Builder {
         self.and_then(|mut option| {
             option.server = addr;
             Ok(option)
         })
     }
 
+    pub fn prefer_ip(self, family: String) -> Builder {
+        self.and_then(|mut option| {
+            option.prefer_ip = family;
+            Ok(option)
+        })
+    }
+
+    pub fn servers(self, servers: Vec<String>) -> Builder {
+        self.and_then(|mut option| {
+            option.servers = servers;
+            Ok(option)
+        })
+    }
+
+    pub fn server_failback(self, failback: bool) -> Builder {
+        self.and_then(|mut option| {
+            option.server_failback = failback;
+            Ok(option)
+        })
+    }
+
+    pub fn server_name(self, name: Option<String>) -> Builder {
+        self.and_then(|mut option| {
+            option.server_name = name;
+            Ok(option)
+        })
+    }
+
+    pub fn admin_listen(self, addr: Option<SocketAddr>) -> Builder {
+        self.and_then(|mut option| {
+            option.admin_listen = addr;
+            Ok(option)
+        })
+    }
+
+    pub fn admin_token(self, token: Option<String>) -> Builder {
+        self.and_then(|mut option| {
+            option.admin_token = token;
+            Ok(option)
+        })
+    }
+
+    pub fn heartbeat_interval(self, interval: u64) -> Builder {
+        self.and_then(|mut option| {
+            option.heartbeat_interval = interval;
+            Ok(option)
+        })
+    }
+
+    pub fn heartbeat_timeout(self, timeout: u64) -> Builder {
+        self.and_then(|mut option| {
+            option.heartbeat_timeout = timeout;
+            Ok(option)
+        })
+    }
+
+    pub fn reconnect_delay(self, delay: u64) -> Builder {
+        self.and_then(|mut option| {
+            option.reconnect_delay = delay;
+            Ok(option)
+        })
+    }
+
+    pub fn reconnect_max_delay(self, delay: u64) -> Builder {
+        self.and_then(|mut option| {
+            option.reconnect_max_delay = delay;
+            Ok(option)
+        })
+    }
+
+    pub fn reconnect_multiplier(self, multiplier: f64) -> Builder {
+        self.and_then(|mut option| {
+            option.reconnect_multiplier = multiplier;
+            Ok(option)
+        })
+    }
+
+    pub fn reconnect_jitter(self, jitter: f64) -> Builder {
+        self.and_then(|mut option| {
+            option.reconnect_jitter = jitter;
+            Ok(option)
+        })
+    }
+
+    pub fn reconnect_max_attempts(self, attempts: u32) -> Builder {
+        self.and_then(|mut option| {
+            option.reconnect_max_attempts = attempts;
+            Ok(option)
+        })
+    }
+
+    pub fn client_name(self, name: Option<String>) -> Builder {
+        self.and_then(|mut option| {
+            option.client_name = name;
+            Ok(option)
+        })
+    }
+
     pub fn signal_port(self, port: u16) -> Builder {
         self.and_then(|mut option| {
             option.signal_port = port;
@@ -56,13 +174,27 @@ impl Builder {
         })
     }
 
-    pub fn data_port(self, port: u16) -> Builder {
+    pub fn data_port(self, port: Option<u16>) -> Builder {
         self.and_then(|mut option| {
             option.data_port = port;
             Ok(option)
         })
     }
 
+    pub fn mux(self, mux: bool) -> Builder {
+        self.and_then(|mut option| {
+            option.mux = mux;
+            Ok(option)
+        })
+    }
+
+    pub fn mux_channels(self, channels: usize) -> Builder {
+        self.and_then(|mut option| {
+            option.mux_channels = channels;
+            Ok(option)
+        })
+    }
+
     pub fn ca_cert(self, ca_cert: Option<String>) -> Builder {
         self.and_then(|mut option| {
             option.ca_cert = ca_cert;
@@ -115,6 +247,21 @@ impl Builder {
     }
 
 
+    pub fn client_policies(self, policies: String) -> Builder {
+        self.and_then(|mut option| {
+            match serde_json::from_str(&policies) {
+                Ok(val) => {
+                    option.client_policies = val;
+                },
+                Err(e) => {
+                    log::error!("Failed to parse client policies:[{}] error{}", policies, e);
+                    panic!("Failed to parse client policies:[{}] error{}", policies, e);
+                }
+            }
+            Ok(option)
+        })
+    }
+
     fn and_then<F>(self, func: F) -> Self
     where
         F: FnOnce(AppOption) -> AppResult<AppOption>,
@@ -129,21 +276,126 @@ fn default_proxy_on() -> Vec<String> {
     vec![String::from("tcp")]
 }
 
+fn default_connect_mode() -> String {
+    String::from("active")
+}
+
 fn default_listen_addr() -> IpAddr {
     "0.0.0.0".parse().unwrap()
 }
 
+fn default_data_port() -> Option<u16> {
+    Some(8002)
+}
+
+/// 数据端口, `none` 表示不监听数据端口
+fn parse_data_port(value: &str) -> AppResult<Option<u16>> {
+    if value.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    let port = value.parse::<u16>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid data_port {}: {}", value, e)))?;
+    Ok(Some(port))
+}
+
+fn default_heartbeat_interval() -> u64 {
+    10
+}
+
+fn default_heartbeat_timeout() -> u64 {
+    30
+}
+
+fn default_reconnect_delay() -> u64 {
+    1
+}
+
+fn default_reconnect_max_delay() -> u64 {
+    60
+}
+
+fn default_reconnect_multiplier() -> f64 {
+    2.0
+}
+
+fn default_reconnect_jitter() -> f64 {
+    0.2
+}
+
+fn default_mux() -> bool {
+    true
+}
+
+fn default_mux_channels() -> usize {
+    1
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AppOption {
     #[serde(default)]
     pub role: String,
 
+    /// active: 客户端连接服务端; passive: 客户端监听, 服务端连接客户端
+    #[serde(default = "default_connect_mode")]
+    pub connect_mode: String,
+    /// 被动模式下服务端需要连接的客户端地址, 格式 host:port
+    #[serde(default)]
+    pub client_addrs: Vec<String>,
+
+    /// 监听地址, `::` 同时监听 IPv4 和 IPv6
     #[serde(default = "default_listen_addr")]
     pub listen: IpAddr,
     pub signal_port: u16,
-    pub data_port: u16,
+    /// 独立数据连接端口, 默认 8002, 配置为 null 时只能通过多路复用通道转发
+    #[serde(default = "default_data_port")]
+    pub data_port: Option<u16>,
+
+    /// 客户端是否通过多路复用通道转发数据
+    #[serde(default = "default_mux")]
+    pub mux: bool,
+    /// 客户端建立的多路复用连接数
+    #[serde(default = "default_mux_channels")]
+    pub mux_channels: usize,
+
+    /// 服务端地址, 格式 host 或 host:port, 未指定端口时使用 signal_port, 每次重连时重新解析
+    pub server: Option<String>,
+    /// 多个服务端地址, 按优先级排列, 当前服务端不可用时依次切换, 配置后忽略 server
+    #[serde(default)]
+    pub servers: Vec<String>,
+    /// 连接到非首选服务端时, 定期检查并切回优先级更高的服务端
+    #[serde(default)]
+    pub server_failback: bool,
+    /// 校验服务端证书使用的名称, 默认为 server 中的 host
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// 域名解析得到多个地址时优先连接的地址族: any, ipv4, ipv6
+    #[serde(default)]
+    pub prefer_ip: String,
+
+    /// 信令连接发送心跳的间隔秒数
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// 超过该秒数没有收到对端消息时断开信令连接
+    #[serde(default = "default_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+
+    /// 客户端重连的初始等待秒数, 每次失败后乘以 reconnect_multiplier, 不超过 reconnect_max_delay
+    #[serde(default = "default_reconnect_delay")]
+    pub reconnect_delay: u64,
+    #[serde(default = "default_reconnect_max_delay")]
+    pub reconnect_max_delay: u64,
+    #[serde(default = "default_reconnect_multiplier")]
+    pub reconnect_multiplier: f64,
+    /// 等待时间的随机抖动比例, 0.2 表示在 [0.8, 1.2] 倍之间浮动
+    #[serde(default = "default_reconnect_jitter")]
+    pub reconnect_jitter: f64,
+    /// 连续重连失败的次数上限, 超过后退出, 0 表示不限制
+    #[serde(default)]
+    pub reconnect_max_attempts: u32,
 
-    pub server: Option<IpAddr>,
+    /// 客户端名称, 服务端按此名称把映射路由到对应客户端, 必须与证书 CN 或 SAN 一致
+    #[serde(default)]
+    pub client_name: Option<String>,
 
     /// ca证书文件
     pub ca_cert: Option<String>,
@@ -160,19 +412,52 @@ pub struct AppOption {
 
     pub proxy_pass: Option<String>,
 
+    /// 服务端: 静态映射; 客户端: 连接后向服务端声明的映射
     #[serde(default)]
     pub mappings: Vec<MappingConfig>,
-   
+    /// 允许客户端声明映射的策略, 不匹配任何策略的客户端映射会被拒绝
+    #[serde(default)]
+    pub client_policies: Vec<ClientPolicy>,
+
+    /// 管理接口监听地址, 不配置时不启动
+    #[serde(default)]
+    pub admin_listen: Option<SocketAddr>,
+    /// 管理接口的访问令牌, 请求需要带上 `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub admin_token: Option<String>,
+
+    /// 配置文件路径, 收到 SIGHUP 时从该文件重新加载
+    #[serde(skip)]
+    pub config: Option<String>,
+    /// 配置文件修改后自动重新加载
+    #[serde(default)]
+    pub config_watch: bool,
 }
 
 impl Default for AppOption {
     fn default() -> Self {
         Self {
             role: "server".to_string(),
+            connect_mode: default_connect_mode(),
+            client_addrs: vec![],
             listen: default_listen_addr(),
             signal_port: 8001,
-            data_port: 8002,
+            data_port: default_data_port(),
+            mux: default_mux(),
+            mux_channels: default_mux_channels(),
             server: None,
+            servers: vec![],
+            server_failback: false,
+            server_name: None,
+            prefer_ip: String::new(),
+            heartbeat_interval: default_heartbeat_interval(),
+            heartbeat_timeout: default_heartbeat_timeout(),
+            reconnect_delay: default_reconnect_delay(),
+            reconnect_max_delay: default_reconnect_max_delay(),
+            reconnect_multiplier: default_reconnect_multiplier(),
+            reconnect_jitter: default_reconnect_jitter(),
+            reconnect_max_attempts: 0,
+            client_name: None,
         
             ca_cert: None,
             cert: None,
@@ -184,7 +469,13 @@ impl Default for AppOption {
             proxy_pass: None,
             
             mappings: vec![],
-            
+            client_policies: vec![],
+
+            admin_listen: None,
+            admin_token: None,
+
+            config: None,
+            config_watch: false,
         }
     }
 }
@@ -196,9 +487,95 @@ impl AppOption {
         Builder::new()
     }
 
+    /// 读取配置文件
+    pub fn load_config(config: &str) -> AppResult<AppOption> {
+        let mut file = File::open(config)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut option = serde_yaml::from_str::<AppOption>(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", config, e)))?;
+        option.config = Some(config.to_string());
+        Ok(option)
+    }
+
+    pub fn log_level_filter(&self) -> LevelFilter {
+        match self.log_level.as_str() {
+            "trace" => LevelFilter::Trace,
+            "debug" => LevelFilter::Debug,
+            "info" => LevelFilter::Info,
+            "warn" => LevelFilter::Warn,
+            "error" => LevelFilter::Error,
+            _ => LevelFilter::Info
+        }
+    }
+
+    pub fn is_passive(&self) -> bool {
+        self.connect_mode.eq_ignore_ascii_case("passive")
+    }
+
+    /// 按优先级排列的服务端地址
+    pub fn server_endpoints(&self) -> Vec<String> {
+        if self.servers.is_empty() {
+            self.server.iter().cloned().collect()
+        } else {
+            self.servers.clone()
+        }
+    }
+
+    /// 服务端信令连接地址 host:port
+    pub fn server_signal_addr(&self) -> Option<String> {
+        let (host, port) = split_host_port(self.server.as_ref()?).ok()?;
+        Some(join_host_port(&host, port.unwrap_or(self.signal_port)))
+    }
+
+    /// 服务端数据连接地址 host:port
+    pub fn server_data_addr(&self) -> Option<String> {
+        let (host, _) = split_host_port(self.server.as_ref()?).ok()?;
+        Some(join_host_port(&host, self.data_port?))
+    }
+
+    /// 校验服务端证书使用的名称
+    pub fn server_tls_name(&self) -> String {
+        self.server_name.clone()
+            .or_else(|| self.server.as_ref().and_then(|x| split_host_port(x).ok()).map(|x| x.0))
+            .unwrap_or(String::from("localhost"))
+    }
+
+    /// 检查配置, 启动前调用
+    pub fn validate(&self) -> AppResult<()> {
+        self.prefer_ip.parse::<IpFamily>()?;
+        if self.heartbeat_interval == 0 || self.heartbeat_timeout <= self.heartbeat_interval {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid heartbeat, expect 0 < heartbeat_interval < heartbeat_timeout").into());
+        }
+        if !self.reconnect_multiplier.is_finite() || self.reconnect_multiplier < 1.0 || !(0.0..=1.0).contains(&self.reconnect_jitter) || self.reconnect_delay > self.reconnect_max_delay {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "invalid reconnect policy, expect reconnect_multiplier >= 1, 0 <= reconnect_jitter <= 1 and reconnect_delay <= reconnect_max_delay").into());
+        }
+        let endpoints = self.server_endpoints();
+        for server in &endpoints {
+            split_host_port(server)?;
+        }
+        if endpoints.is_empty() && self.role == "client" && !self.is_passive() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "server address is required for active client").into());
+        }
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            mapping.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            if self.mappings[..i].iter().any(|x| x.name == mapping.name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("mapping {}: duplicate name", mapping.name)).into());
+            }
+            if self.role == "client" && mapping.listen.is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("mapping {}: listen is required", mapping.name)).into());
+            }
+        }
+        for policy in &self.client_policies {
+            policy.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        Ok(())
+    }
+
     pub fn parse_env() -> AppResult<AppOption> {
         let command = Commander::new()
-            .version(&env!("CARGO_PKG_VERSION").to_string())
+            .version(env!("CARGO_PKG_VERSION"))
             .usage("--listen 0.0.0.0")
             .usage_desc("natproxy -R server --listen 0.0.0.0")
             .option_list( "--proxy_on [value]", "proxy enables: http https socks5 tcp httpreverse", None, )
@@ -207,42 +584,106 @@ impl AppOption {
             .option_str("--ca value", "The trusted CA certificate file in PEM format used to verify the cert", None)
             .option_str("--cert value", "Certificate used for mTLS between server/client nodes.", None)
             .option_str("--key value", "Certificate key", None).option_str( "-L, --listen value", "server listen address", Some("0.0.0.0".to_string()),)
-            .option_str("--data_port value", "server port for forward data: default 8002", None)
+            .option_str("--data_port value", "server port for forward data, none to disable: default 8002", None)
+            .option_str("--mux value", "forward data over multiplexed connections: default true", None)
+            .option_str("--mux_channels value", "multiplexed connections per client: default 1", None)
             .option_str("--signal_port value", "server port for signal msg: default 8001", None)
-            .option_str("-S, --server value", "server address: 127.0.0.1:8001", None)
+            .option_str("-S, --server value", "server address: 127.0.0.1:8001 or proxy.example.com", None)
+            .option_str("--servers value", "server addresses in priority order: relay1:8001,relay2:8001", None)
+            .option_str("--server_failback value", "switch back to a preferred server when it is available again: default false", None)
+            .option_str("--prefer_ip value", "address family tried first for hostnames: any, ipv4, ipv6", None)
+            .option_str("--server_name value", "server name used to verify the server certificate: default server host", None)
+            .option_str("--connect_mode value", "active: client connect to server, passive: server connect to client", None)
+            .option_str("--client_addrs value", "passive client addresses for server: 10.0.0.2:8001,10.0.0.3:8001", None)
+            .option_str("--heartbeat_interval value", "seconds between heartbeats on the signal connection: default 10", None)
+            .option_str("--heartbeat_timeout value", "close the signal connection after this many seconds without heartbeat: default 30", None)
+            .option_str("--reconnect_delay value", "initial client reconnect delay in seconds: default 1", None)
+            .option_str("--reconnect_max_delay value", "max client reconnect delay in seconds: default 60", None)
+            .option_str("--reconnect_multiplier value", "client reconnect delay multiplier: default 2", None)
+            .option_str("--reconnect_jitter value", "random jitter ratio of reconnect delay: default 0.2", None)
+            .option_str("--reconnect_max_attempts value", "exit after this many failed reconnects, 0 for unlimited: default 0", None)
+            .option_str("-N, --name value", "client name used by server mappings: default certificate CN", None)
             .option_str("--pass value", "proxy password", None)
             .option_str("--log value", "log level", None)
             .option_str("--mappings value", "proxy mappings", None)
+            .option_str("--client_policies value", "listen ports clients may declare mappings on", None)
+            .option_str("--admin_listen value", "admin api listen address: 127.0.0.1:8090", None)
+            .option_str("--admin_token value", "bearer token required by the admin api", None)
             .parse_env_or_exit();
 
         if let Some(config) = command.get_str("c") {
-            let mut file = File::open(config)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            let option = serde_yaml::from_str::<AppOption>(&contents).unwrap();
+            let option = Self::load_config(&config)?;
             log::debug!("options = {:?}", option);
             return Ok(option);
         }
 
         let mut builder = Self::builder();
         for (k,v) in env::vars() {
-            if k.starts_with(NATPROXY_ENV_PREFIX) {
+            if let Some(name) = k.strip_prefix(NATPROXY_ENV_PREFIX) {
                 log::trace!("env key:{}  value:{}", k, v);
-                match &k[NATPROXY_ENV_PREFIX.len()..] {
+                match name {
                     "ROLE" => {
                         builder = builder.role(v);
                     }
+                    "CONNECT_MODE" => {
+                        builder = builder.connect_mode(v);
+                    }
+                    "CLIENT_ADDRS" => {
+                        builder = builder.client_addrs(v.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect());
+                    }
                     "LISTEN" => {
                         builder = builder.listen_addr(v.parse::<IpAddr>().unwrap());
                     }
                     "SERVER" => {
-                        builder = builder.server(v.parse().ok());
+                        builder = builder.server(Some(v));
+                    }
+                    "PREFER_IP" => {
+                        builder = builder.prefer_ip(v);
+                    }
+                    "SERVERS" => {
+                        builder = builder.servers(v.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect());
+                    }
+                    "SERVER_FAILBACK" => {
+                        builder = builder.server_failback(v.parse::<bool>().unwrap());
+                    }
+                    "SERVER_NAME" => {
+                        builder = builder.server_name(Some(v));
+                    }
+                    "HEARTBEAT_INTERVAL" => {
+                        builder = builder.heartbeat_interval(v.parse::<u64>().unwrap());
+                    }
+                    "HEARTBEAT_TIMEOUT" => {
+                        builder = builder.heartbeat_timeout(v.parse::<u64>().unwrap());
+                    }
+                    "RECONNECT_DELAY" => {
+                        builder = builder.reconnect_delay(v.parse::<u64>().unwrap());
+                    }
+                    "RECONNECT_MAX_DELAY" => {
+                        builder = builder.reconnect_max_delay(v.parse::<u64>().unwrap());
+                    }
+                    "RECONNECT_MULTIPLIER" => {
+                        builder = builder.reconnect_multiplier(v.parse::<f64>().unwrap());
+                    }
+                    "RECONNECT_JITTER" => {
+                        builder = builder.reconnect_jitter(v.parse::<f64>().unwrap());
+                    }
+                    "RECONNECT_MAX_ATTEMPTS" => {
+                        builder = builder.reconnect_max_attempts(v.parse::<u32>().unwrap());
+                    }
+                    "CLIENT_NAME" => {
+                        builder = builder.client_name(Some(v));
                     }
                     "SIGNAL_PORT" => {
                         builder = builder.signal_port(v.parse::<u16>().unwrap());
                     }
                     "DATA_PORT" => {
-                        builder = builder.data_port(v.parse::<u16>().unwrap());
+                        builder = builder.data_port(parse_data_port(&v)?);
+                    }
+                    "MUX" => {
+                        builder = builder.mux(v.parse::<bool>().unwrap());
+                    }
+                    "MUX_CHANNELS" => {
+                        builder = builder.mux_channels(v.parse::<usize>().unwrap());
                     }
                     "CA_CERT" => {
                         builder = builder.ca_cert(Some(v));
@@ -262,6 +703,15 @@ impl AppOption {
                     "MAPPINGS" => {
                         builder = builder.mappings(v);
                     }
+                    "CLIENT_POLICIES" => {
+                        builder = builder.client_policies(v);
+                    }
+                    "ADMIN_LISTEN" => {
+                        builder = builder.admin_listen(Some(v.parse::<SocketAddr>().unwrap()));
+                    }
+                    "ADMIN_TOKEN" => {
+                        builder = builder.admin_token(Some(v));
+                    }
                     _ => {}
                 }
             }
@@ -269,72 +719,187 @@ impl AppOption {
         }
 
         let listen_host = command.get_str("listen");
-        match listen_host {
-            Some(val) => builder = builder.listen_addr(val.parse::<IpAddr>().unwrap()),
-            None => {}
+        if let Some(val) = listen_host {
+            builder = builder.listen_addr(val.parse::<IpAddr>().unwrap());
         }
 
         let v = command.get_str("signal_port");
-        match v {
-            Some(val) => builder = builder.signal_port(val.parse::<u16>().unwrap()),
-            None => {}
+        if let Some(val) = v {
+            builder = builder.signal_port(val.parse::<u16>().unwrap());
         }
 
         let v = command.get_str("data_port");
-        match v {
-            Some(val) => builder = builder.data_port(val.parse::<u16>().unwrap()),
-            None => {}
+        if let Some(val) = v {
+            builder = builder.data_port(parse_data_port(&val)?);
+        }
+
+        let v = command.get_str("mux");
+        if let Some(val) = v {
+            builder = builder.mux(val.parse::<bool>().unwrap());
+        }
+
+        let v = command.get_str("mux_channels");
+        if let Some(val) = v {
+            builder = builder.mux_channels(val.parse::<usize>().unwrap());
+        }
+
+        let v = command.get_str("connect_mode");
+        if let Some(val) = v {
+            builder = builder.connect_mode(val);
+        }
+
+        let v = command.get_str("client_addrs");
+        if let Some(val) = v {
+            builder = builder.client_addrs(val.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect());
         }
 
         let role = command.get_str("role");
-        match role {
-            Some(val) => builder = builder.role(val),
-            None => {}
+        if let Some(val) = role {
+            builder = builder.role(val);
         }
 
         let pass = command.get_str("pass");
-        match pass {
-            Some(val) => builder = builder.password(Some(val)),
-            None => {}
+        if let Some(val) = pass {
+            builder = builder.password(Some(val));
         }
 
 
         let ca = command.get_str("ca");
-        match ca {
-            Some(val) => builder = builder.ca_cert(Some(val)),
-            None => {}
+        if let Some(val) = ca {
+            builder = builder.ca_cert(Some(val));
         }
 
         let cert = command.get_str("cert");
-        match cert {
-            Some(val) => builder = builder.cert(Some(val)),
-            None => {}
+        if let Some(val) = cert {
+            builder = builder.cert(Some(val));
         }
 
         let key = command.get_str("key");
-        match key {
-            Some(val) => builder = builder.key(Some(val)),
-            None => {}
+        if let Some(val) = key {
+            builder = builder.key(Some(val));
         }
 
         let log = command.get_str("log");
-        match log {
-            Some(val) => builder = builder.log_level(Some(val)),
-            None => {}
+        if let Some(val) = log {
+            builder = builder.log_level(Some(val));
         }
 
         let server = command.get_str("S");
-        match server {
-            Some(val) => builder = builder.server(val.parse::<IpAddr>().ok()),
-            None => {}
+        if let Some(val) = server {
+            builder = builder.server(Some(val));
+        }
+
+        let servers = command.get_str("servers");
+        if let Some(val) = servers {
+            builder = builder.servers(val.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect());
+        }
+
+        let v = command.get_str("server_failback");
+        if let Some(val) = v {
+            builder = builder.server_failback(val.parse::<bool>().unwrap());
+        }
+
+        let prefer_ip = command.get_str("prefer_ip");
+        if let Some(val) = prefer_ip {
+            builder = builder.prefer_ip(val);
+        }
+
+        let server_name = command.get_str("server_name");
+        if let Some(val) = server_name {
+            builder = builder.server_name(Some(val));
+        }
+
+        let v = command.get_str("heartbeat_interval");
+        if let Some(val) = v {
+            builder = builder.heartbeat_interval(val.parse::<u64>().unwrap());
+        }
+
+        let v = command.get_str("heartbeat_timeout");
+        if let Some(val) = v {
+            builder = builder.heartbeat_timeout(val.parse::<u64>().unwrap());
+        }
+
+        let v = command.get_str("reconnect_delay");
+        if let Some(val) = v {
+            builder = builder.reconnect_delay(val.parse::<u64>().unwrap());
+        }
+
+        let v = command.get_str("reconnect_max_delay");
+        if let Some(val) = v {
+            builder = builder.reconnect_max_delay(val.parse::<u64>().unwrap());
+        }
+
+        let v = command.get_str("reconnect_multiplier");
+        if let Some(val) = v {
+            builder = builder.reconnect_multiplier(val.parse::<f64>().unwrap());
+        }
+
+        let v = command.get_str("reconnect_jitter");
+        if let Some(val) = v {
+            builder = builder.reconnect_jitter(val.parse::<f64>().unwrap());
+        }
+
+        let v = command.get_str("reconnect_max_attempts");
+        if let Some(val) = v {
+            builder = builder.reconnect_max_attempts(val.parse::<u32>().unwrap());
+        }
+
+        let name = command.get_str("name");
+        if let Some(val) = name {
+            builder = builder.client_name(Some(val));
         }
 
         let mappings = command.get_str("mappings");
-        match mappings {
-            Some(val) => builder = builder.mappings(val),
-            None => {}
+        if let Some(val) = mappings {
+            builder = builder.mappings(val);
+        }
+
+        let policies = command.get_str("client_policies");
+        if let Some(val) = policies {
+            builder = builder.client_policies(val);
+        }
+
+        let v = command.get_str("admin_listen");
+        if let Some(val) = v {
+            builder = builder.admin_listen(Some(val.parse::<SocketAddr>().unwrap()));
+        }
+
+        let v = command.get_str("admin_token");
+        if let Some(val) = v {
+            builder = builder.admin_token(Some(val));
         }
       
         builder.inner
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_port_defaults_when_omitted() {
+        let option: AppOption = serde_yaml::from_str("role: server\nsignal_port: 8001\n").unwrap();
+        assert_eq!(option.data_port, Some(8002));
+        let option: AppOption = serde_yaml::from_str("role: server\nsignal_port: 8001\ndata_port: null\n").unwrap();
+        assert_eq!(option.data_port, None);
+    }
+
+    #[test]
+    fn data_port_parse_error_is_reported() {
+        assert_eq!(parse_data_port("9002").unwrap(), Some(9002));
+        assert_eq!(parse_data_port("none").unwrap(), None);
+        assert!(parse_data_port("80o2").is_err());
+        assert!(parse_data_port("70000").is_err());
+    }
+
+    #[test]
+    fn validate_reconnect_policy() {
+        assert!(AppOption::default().validate().is_ok());
+        for (multiplier, jitter) in [(0.5, 0.2), (f64::NAN, 0.2), (f64::INFINITY, 0.2), (2.0, f64::NAN), (2.0, 1.5), (2.0, -0.1)] {
+            let option = AppOption { reconnect_multiplier: multiplier, reconnect_jitter: jitter, ..Default::default() };
+            assert!(option.validate().is_err(), "{} {}", multiplier, jitter);
+        }
+        let option = AppOption { reconnect_delay: 60, reconnect_max_delay: 10, ..Default::default() };
+        assert!(option.validate().is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::io;
+
 use serde::{Deserialize, Serialize};
 use crate::MappingConfig;
 use crate::{
@@ -5,81 +7,221 @@ use crate::{
     generate_uuid,
 };
 
-lazy_static! {
-    static ref PROTO_CMD:Vec<u8> = vec![0x18u8, 0x11u8];
+/// 处理成功
+pub const STATUS_OK: &str = "Ok";
+/// 其它错误
+pub const STATUS_ERROR: &str = "Error";
+/// 目标拒绝连接
+pub const STATUS_CONNECTION_REFUSED: &str = "ConnectionRefused";
+/// 目标无法解析或不可达
+pub const STATUS_HOST_UNREACHABLE: &str = "HostUnreachable";
+/// 等待超时
+pub const STATUS_TIMEOUT: &str = "Timeout";
+/// 没有可用的客户端或转发通道
+pub const STATUS_UNAVAILABLE: &str = "Unavailable";
+/// 对端不支持的命令
+pub const STATUS_UNSUPPORTED: &str = "Unsupported";
+
+/// 连接目标失败时返回给服务端的状态
+pub fn error_status(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => STATUS_CONNECTION_REFUSED,
+        io::ErrorKind::TimedOut => STATUS_TIMEOUT,
+        io::ErrorKind::NotFound
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable
+        | io::ErrorKind::AddrNotAvailable => STATUS_HOST_UNREACHABLE,
+        _ => STATUS_ERROR,
+    }
 }
 
+/// 服务端请求客户端建立转发连接
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProxyRequest {
+    pub bind_id: String,
+    pub client: String,
+    pub mapping: Box<MappingConfig>,
+    /// 数据连接凭证, 客户端建立数据连接时原样带回
+    #[serde(default)]
+    pub token: String,
+    /// 代理模式下的目标地址 `host:port`, 为空时连接 `mapping.forward`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
 
+/// 客户端声明自己的映射
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-#[serde(untagged)]
+pub struct ClientConf {
+    pub mappings: Vec<MappingConfig>,
+}
+
+/// 命令类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CmdKind {
+    Conn,
+    Conf,
+    Ping,
+    /// 本端不认识的命令, 通常来自更新版本的对端
+    #[serde(other)]
+    Unknown,
+}
+
+/// 命令类型和对应的内容, 序列化为 `"cmd_type": "conn", "body": {...}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd_type", content = "body", rename_all = "lowercase", try_from = "RawCmdBody")]
 pub enum ProtoCmdBody {
-    ClientConfData {
-        mappings: Vec<MappingConfig>,
-    },
-
-    ProxyRequest {
-        bind_id: String,
-        client: String,
-        mapping: MappingConfig,    
-    },
-
-    ProxyResponse {
-        bind_id: String,
-        client: String,
-        mapping_name: String,
-    },
+    Conn(ProxyRequest),
+    Conf(ClientConf),
+    Ping,
+    /// 本端不认识的命令类型, 只用于接收
+    #[serde(skip_serializing)]
+    Unknown(String),
+}
+
+/// 先按字符串读取命令类型, 不认识的类型连同内容一起忽略
+#[derive(Deserialize)]
+struct RawCmdBody {
+    cmd_type: String,
+    #[serde(default)]
+    body: serde_json::Value,
+}
+
+impl TryFrom<RawCmdBody> for ProtoCmdBody {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: RawCmdBody) -> Result<Self, Self::Error> {
+        Ok(match raw.cmd_type.as_str() {
+            "conn" => ProtoCmdBody::Conn(serde_json::from_value(raw.body)?),
+            "conf" => ProtoCmdBody::Conf(serde_json::from_value(raw.body)?),
+            "ping" => ProtoCmdBody::Ping,
+            _ => ProtoCmdBody::Unknown(raw.cmd_type),
+        })
+    }
+}
+
+impl ProtoCmdBody {
+    pub fn kind(&self) -> CmdKind {
+        match self {
+            ProtoCmdBody::Conn(_) => CmdKind::Conn,
+            ProtoCmdBody::Conf(_) => CmdKind::Conf,
+            ProtoCmdBody::Ping => CmdKind::Ping,
+            ProtoCmdBody::Unknown(_) => CmdKind::Unknown,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ProtoCmdRequest {
     pub id: String,
-    pub cmd_type: String,
-    pub body: Option<ProtoCmdBody>,
+    #[serde(flatten)]
+    pub body: ProtoCmdBody,
     pub time: String,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ProtoCmdResponse {
+    /// 原消息ID
     pub id: String,
-    pub cmd_type: String,
+    /// 原消息类型
+    pub cmd_type: CmdKind,
+    /// 请求处理结果状态
     pub status: String,
+    /// 请求处理信息
     pub message: String,
-    pub body: Option<ProtoCmdBody>,
     pub time: String,
 }
+
+/// 响应带有 status 和 message, 需要先于请求尝试解析
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ProtoCmd {
-    Request(ProtoCmdRequest),
     Response(ProtoCmdResponse),
+    Request(ProtoCmdRequest),
 }
 
 impl ProtoCmdRequest {
-    pub fn new(cmd_type: String, body: Option<ProtoCmdBody>) -> Self {
+    pub fn new(body: ProtoCmdBody) -> Self {
         Self { 
             id: generate_uuid(),
-            cmd_type, 
             time: get_datetime14(),
             body,
         }
     }
 
+    pub fn kind(&self) -> CmdKind {
+        self.body.kind()
+    }
+
+    /// 对本请求的响应
+    pub fn response(&self, status: &str, message: String) -> ProtoCmdResponse {
+        ProtoCmdResponse::new(self.id.clone(), self.kind(), status.to_string(), message)
+    }
+
+    /// 不支持的命令, 回复错误而不是断开连接
+    pub fn unsupported(&self) -> ProtoCmd {
+        let name = match &self.body {
+            ProtoCmdBody::Unknown(name) => name.clone(),
+            body => format!("{:?}", body.kind()),
+        };
+        ProtoCmd::Response(self.response(STATUS_UNSUPPORTED, format!("unsupported command: {}", name)))
+    }
 }
 
 impl ProtoCmdResponse {
-    pub fn new(id: String, cmd_type: String, status: String, message: String, body: Option<ProtoCmdBody>) -> Self {
+    pub fn is_ok(&self) -> bool {
+        self.status == STATUS_OK
+    }
+
+    pub fn new(id: String, cmd_type: CmdKind, status: String, message: String) -> Self {
         Self { 
-            /// 原消息ID
             id,  
-            /// 原消息类型
             cmd_type,
-            /// 请求处理结果状态
             status, 
-            /// 请求处理信息
             message, 
             time: get_datetime14(),
-            body,
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_legacy_cmds() {
+        let ping = r#"{"id":"1","cmd_type":"ping","body":null,"time":"20260101000000"}"#;
+        let ProtoCmd::Request(req) = serde_json::from_str::<ProtoCmd>(ping).unwrap() else {
+            panic!("expect request");
+        };
+        assert_eq!(req.body, ProtoCmdBody::Ping);
+
+        let rsp = r#"{"id":"1","cmd_type":"conf","status":"Ok","message":"","body":null,"time":"20260101000000"}"#;
+        let ProtoCmd::Response(rsp) = serde_json::from_str::<ProtoCmd>(rsp).unwrap() else {
+            panic!("expect response");
+        };
+        assert_eq!(rsp.cmd_type, CmdKind::Conf);
+        assert!(rsp.is_ok());
+
+        let conf = r#"{"id":"1","cmd_type":"conf","body":{"mappings":[]},"time":"20260101000000"}"#;
+        let ProtoCmd::Request(req) = serde_json::from_str::<ProtoCmd>(conf).unwrap() else {
+            panic!("expect request");
+        };
+        assert_eq!(req.body, ProtoCmdBody::Conf(ClientConf { mappings: vec![] }));
+    }
+
+    #[test]
+    fn unknown_cmd_is_unsupported() {
+        let cmd = r#"{"id":"1","cmd_type":"reload","body":{"x":1},"time":"20260101000000"}"#;
+        let ProtoCmd::Request(req) = serde_json::from_str::<ProtoCmd>(cmd).unwrap() else {
+            panic!("expect request");
+        };
+        assert_eq!(req.kind(), CmdKind::Unknown);
+        let ProtoCmd::Response(rsp) = req.unsupported() else {
+            panic!("expect response");
+        };
+        assert_eq!(rsp.status, STATUS_UNSUPPORTED);
+        assert_eq!(rsp.id, "1");
+    }
+}
@@ -0,0 +1,273 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::ProtoCmd;
+
+/// 帧头魔数
+pub const PROTO_MAGIC: [u8; 2] = [0x18u8, 0x11u8];
+/// 当前帧格式版本
+pub const PROTO_VERSION: u8 = 1;
+/// magic(2) + version(1) + type(1) + length(4)
+pub const FRAME_HEADER_SIZE: usize = 8;
+/// 单帧负载上限, 超过即视为协议错误
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    /// 连接建立时的握手信息, 如 `main:<client>:<id>`
+    Meta,
+    /// JSON 编码的 `ProtoCmd`
+    Cmd,
+}
+
+impl FrameType {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            FrameType::Meta => 0x01,
+            FrameType::Cmd => 0x02,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<FrameType> {
+        match value {
+            0x01 => Some(FrameType::Meta),
+            0x02 => Some(FrameType::Cmd),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(frame_type: FrameType, payload: Vec<u8>) -> Self {
+        Self { frame_type, payload }
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        if self.payload.len() > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame payload too large"));
+        }
+
+        let mut buf = Vec::with_capacity(FRAME_HEADER_SIZE + self.payload.len());
+        buf.extend_from_slice(&PROTO_MAGIC);
+        buf.push(PROTO_VERSION);
+        buf.push(self.frame_type.as_u8());
+        buf.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        Ok(buf)
+    }
+
+    /// 从缓冲区头部解析一个完整帧, 数据不足时返回 `None`
+    pub fn decode(buffer: &mut Vec<u8>) -> io::Result<Option<Frame>> {
+        if buffer.len() < FRAME_HEADER_SIZE {
+            if !buffer.is_empty() && !PROTO_MAGIC.starts_with(&buffer[..buffer.len().min(2)]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad frame magic"));
+            }
+            return Ok(None);
+        }
+
+        if buffer[0..2] != PROTO_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad frame magic"));
+        }
+        if buffer[2] != PROTO_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported frame version {}", buffer[2])));
+        }
+        let frame_type = FrameType::from_u8(buffer[3])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown frame type {}", buffer[3])))?;
+        let length = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame too large: {}", length)));
+        }
+        if buffer.len() < FRAME_HEADER_SIZE + length {
+            return Ok(None);
+        }
+
+        let payload = buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length].to_vec();
+        buffer.drain(..FRAME_HEADER_SIZE + length);
+        Ok(Some(Frame::new(frame_type, payload)))
+    }
+}
+
+/// 读取一个完整帧.
+///
+/// 未解析完的数据保留在 `buffer` 中, 因此可以安全地用在 `select!` 分支里,
+/// 只要每次调用传入同一个 `buffer`.
+pub async fn read_frame<R>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<Frame>
+where R: AsyncRead + Unpin {
+    loop {
+        if let Some(frame) = Frame::decode(buffer)? {
+            return Ok(frame);
+        }
+
+        buffer.reserve(FRAME_HEADER_SIZE.max(4096));
+        let size = reader.read_buf(buffer).await?;
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+    }
+}
+
+/// 精确读取一个帧, 不会多读帧之后的数据.
+///
+/// 用于握手等随后会把连接交给其它逻辑的场景, 不可在 `select!` 中使用.
+pub async fn read_frame_exact<R>(reader: &mut R) -> io::Result<Frame>
+where R: AsyncRead + Unpin {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let mut buffer = header.to_vec();
+    if let Some(frame) = Frame::decode(&mut buffer)? {
+        return Ok(frame);
+    }
+
+    let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+    buffer.extend_from_slice(&payload);
+    Frame::decode(&mut buffer)?.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "incomplete frame"))
+}
+
+pub async fn write_frame<W>(writer: &mut W, frame_type: FrameType, payload: &[u8]) -> io::Result<()>
+where W: AsyncWrite + Unpin {
+    let data = Frame::new(frame_type, payload.to_vec()).encode()?;
+    writer.write_all(&data).await?;
+    writer.flush().await
+}
+
+pub async fn read_meta<R>(reader: &mut R) -> io::Result<String>
+where R: AsyncRead + Unpin {
+    let frame = read_frame_exact(reader).await?;
+    if frame.frame_type != FrameType::Meta {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expect meta frame"));
+    }
+    String::from_utf8(frame.payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub async fn write_meta<W>(writer: &mut W, meta: &str) -> io::Result<()>
+where W: AsyncWrite + Unpin {
+    write_frame(writer, FrameType::Meta, meta.as_bytes()).await
+}
+
+fn decode_cmd(frame: Frame) -> io::Result<ProtoCmd> {
+    if frame.frame_type != FrameType::Cmd {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expect cmd frame"));
+    }
+    serde_json::from_slice(&frame.payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub async fn read_cmd<R>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<ProtoCmd>
+where R: AsyncRead + Unpin {
+    decode_cmd(read_frame(reader, buffer).await?)
+}
+
+pub async fn read_cmd_exact<R>(reader: &mut R) -> io::Result<ProtoCmd>
+where R: AsyncRead + Unpin {
+    decode_cmd(read_frame_exact(reader).await?)
+}
+
+pub async fn write_cmd<W>(writer: &mut W, cmd: &ProtoCmd) -> io::Result<()>
+where W: AsyncWrite + Unpin {
+    let json = serde_json::to_vec(cmd).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    log::trace!("send cmd: {}", String::from_utf8_lossy(&json));
+    write_frame(writer, FrameType::Cmd, &json).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_data(result: io::Result<Option<Frame>>) -> bool {
+        matches!(result, Err(e) if e.kind() == io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let meta = Frame::new(FrameType::Meta, b"main:client1:id".to_vec());
+        let cmd = Frame::new(FrameType::Cmd, b"{}".to_vec());
+        let empty = Frame::new(FrameType::Cmd, vec![]);
+        let mut buffer = meta.encode().unwrap();
+        buffer.extend(cmd.encode().unwrap());
+        buffer.extend(empty.encode().unwrap());
+
+        assert_eq!(Frame::decode(&mut buffer).unwrap(), Some(meta));
+        assert_eq!(Frame::decode(&mut buffer).unwrap(), Some(cmd));
+        assert_eq!(Frame::decode(&mut buffer).unwrap(), Some(empty));
+        assert!(buffer.is_empty());
+        assert_eq!(Frame::decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_rejects_bad_magic() {
+        let mut buffer = Frame::new(FrameType::Meta, b"x".to_vec()).encode().unwrap();
+        buffer[1] = 0;
+        assert!(invalid_data(Frame::decode(&mut buffer)));
+        // 不足一个帧头时也能尽早发现
+        assert!(invalid_data(Frame::decode(&mut vec![0x47])));
+        assert_eq!(Frame::decode(&mut vec![PROTO_MAGIC[0]]).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_rejects_bad_version_and_type() {
+        let mut buffer = Frame::new(FrameType::Meta, b"x".to_vec()).encode().unwrap();
+        buffer[2] = PROTO_VERSION + 1;
+        assert!(invalid_data(Frame::decode(&mut buffer)));
+
+        let mut buffer = Frame::new(FrameType::Meta, b"x".to_vec()).encode().unwrap();
+        buffer[3] = 0x7f;
+        assert!(invalid_data(Frame::decode(&mut buffer)));
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        assert!(Frame::new(FrameType::Cmd, vec![0; MAX_FRAME_SIZE + 1]).encode().is_err());
+
+        let mut buffer = PROTO_MAGIC.to_vec();
+        buffer.extend([PROTO_VERSION, FrameType::Cmd.as_u8()]);
+        buffer.extend(((MAX_FRAME_SIZE + 1) as u32).to_be_bytes());
+        assert!(invalid_data(Frame::decode(&mut buffer)));
+    }
+
+    #[tokio::test]
+    async fn read_frame_across_partial_reads() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let first = Frame::new(FrameType::Meta, b"main:client1:id".to_vec()).encode().unwrap();
+        let second = Frame::new(FrameType::Cmd, b"{\"type\":\"ping\"}".to_vec()).encode().unwrap();
+        let mut data = first.clone();
+        data.extend(&second);
+
+        let writer = tokio::spawn(async move {
+            for chunk in data.chunks(3) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut buffer = Vec::new();
+        let frame = read_frame(&mut server, &mut buffer).await.unwrap();
+        assert_eq!(frame.payload, b"main:client1:id");
+        let frame = read_frame(&mut server, &mut buffer).await.unwrap();
+        assert_eq!(frame.frame_type, FrameType::Cmd);
+        assert_eq!(frame.payload, b"{\"type\":\"ping\"}");
+        writer.await.unwrap();
+
+        let err = read_frame(&mut server, &mut buffer).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn read_frame_exact_leaves_following_data() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_meta(&mut client, "data:client1:id:token").await.unwrap();
+        client.write_all(b"raw").await.unwrap();
+
+        assert_eq!(read_meta(&mut server).await.unwrap(), "data:client1:id:token");
+        let mut rest = [0u8; 3];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"raw");
+    }
+}
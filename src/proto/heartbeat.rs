@@ -0,0 +1,92 @@
+use std::io;
+
+use tokio::time::{interval_at, Duration, Instant, Interval, MissedTickBehavior};
+
+use super::{ProtoCmd, ProtoCmdBody, ProtoCmdRequest, STATUS_OK};
+
+/// 信令连接心跳, 两端都定期发送 ping, 超过 `timeout` 没有收到对端任何消息时判定连接失效
+pub struct Heartbeat {
+    ticker: Interval,
+    timeout: Duration,
+    last_recv: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval: u64, timeout: u64) -> Heartbeat {
+        let period = Duration::from_secs(interval.max(1));
+        let mut ticker = interval_at(Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Heartbeat {
+            ticker,
+            timeout: Duration::from_secs(timeout),
+            last_recv: Instant::now(),
+        }
+    }
+
+    /// 收到对端消息
+    pub fn received(&mut self) {
+        self.last_recv = Instant::now();
+    }
+
+    /// 等待下一次心跳, 返回需要发送的 ping, 对端超时返回错误. 可以在 `select!` 中使用
+    pub async fn tick(&mut self) -> io::Result<ProtoCmd> {
+        self.ticker.tick().await;
+        if self.last_recv.elapsed() > self.timeout {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no heartbeat from peer in {} seconds", self.timeout.as_secs())));
+        }
+        Ok(ProtoCmd::Request(ProtoCmdRequest::new(ProtoCmdBody::Ping)))
+    }
+}
+
+/// ping 的响应
+pub fn pong(req: &ProtoCmdRequest) -> ProtoCmd {
+    ProtoCmd::Response(req.response(STATUS_OK, String::from("pong")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::CmdKind;
+
+    #[tokio::test(start_paused = true)]
+    async fn tick_sends_ping_every_interval() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(10, 30);
+        let cmd = heartbeat.tick().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert!(matches!(cmd, ProtoCmd::Request(req) if req.kind() == CmdKind::Ping));
+
+        heartbeat.received();
+        heartbeat.tick().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tick_fails_after_timeout() {
+        let mut heartbeat = Heartbeat::new(10, 25);
+        heartbeat.tick().await.unwrap();
+        heartbeat.tick().await.unwrap();
+        let err = heartbeat.tick().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn received_keeps_connection_alive() {
+        let mut heartbeat = Heartbeat::new(10, 25);
+        for _ in 0..10 {
+            heartbeat.tick().await.unwrap();
+            heartbeat.received();
+        }
+    }
+
+    #[test]
+    fn pong_answers_ping() {
+        let req = ProtoCmdRequest::new(ProtoCmdBody::Ping);
+        let ProtoCmd::Response(rsp) = pong(&req) else {
+            panic!("expect response");
+        };
+        assert_eq!(rsp.id, req.id);
+        assert_eq!(rsp.cmd_type, CmdKind::Ping);
+        assert!(rsp.is_ok());
+    }
+}
@@ -1,8 +1,25 @@
 mod cmd;
+mod frame;
+mod heartbeat;
+mod version;
 
 pub use cmd::{
     ProtoCmd,
     ProtoCmdRequest,
     ProtoCmdResponse,
     ProtoCmdBody,
-};
\ No newline at end of file
+    ProxyRequest,
+    ClientConf,
+    CmdKind,
+    error_status,
+    STATUS_OK,
+    STATUS_ERROR,
+    STATUS_CONNECTION_REFUSED,
+    STATUS_HOST_UNREACHABLE,
+    STATUS_TIMEOUT,
+    STATUS_UNAVAILABLE,
+    STATUS_UNSUPPORTED,
+};
+pub use frame::*;
+pub use heartbeat::*;
+pub use version::*;
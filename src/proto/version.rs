@@ -0,0 +1,93 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{read_frame, write_meta, FrameType};
+
+/// 当前信令命令的协议版本, 与帧格式版本 `PROTO_VERSION` 相互独立
+pub const CMD_VERSION: u32 = 2;
+/// 握手时不声明版本的旧客户端
+pub const CMD_VERSION_LEGACY: u32 = 1;
+
+/// 根据客户端在握手 `main:name:id:version` 中声明的版本, 得到双方都支持的版本
+pub fn negotiate_version(declared: &str) -> Option<u32> {
+    let version: u32 = declared.parse().ok()?;
+    if version < CMD_VERSION_LEGACY {
+        return None;
+    }
+    Some(version.min(CMD_VERSION))
+}
+
+/// 服务端回复协商后的版本
+pub async fn write_version<W>(writer: &mut W, version: u32) -> io::Result<()>
+where W: AsyncWrite + Unpin {
+    write_meta(writer, &format!("version:{}", version)).await
+}
+
+/// 客户端读取服务端回复的版本.
+///
+/// 旧服务端不回复版本, 直接开始发送命令. 此时把读到的命令帧放回 `buffer`, 按旧版本处理,
+/// 随后的信令循环需要继续使用同一个 `buffer`
+pub async fn read_version<R>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<u32>
+where R: AsyncRead + Unpin {
+    let frame = read_frame(reader, buffer).await?;
+    if frame.frame_type == FrameType::Cmd {
+        let data = frame.encode()?;
+        buffer.splice(0..0, data);
+        return Ok(CMD_VERSION_LEGACY);
+    }
+
+    let meta = String::from_utf8_lossy(&frame.payload);
+    meta.strip_prefix("version:")
+        .and_then(|x| x.parse().ok())
+        .filter(|x| (CMD_VERSION_LEGACY..=CMD_VERSION).contains(x))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unexpected protocol version: {}", meta)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::proto::{read_cmd, write_cmd, ProtoCmd, ProtoCmdBody, ProtoCmdRequest};
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(negotiate_version("1"), Some(1));
+        assert_eq!(negotiate_version("2"), Some(2));
+        assert_eq!(negotiate_version("9"), Some(CMD_VERSION));
+        assert_eq!(negotiate_version("0"), None);
+        assert_eq!(negotiate_version("x"), None);
+    }
+
+    #[tokio::test]
+    async fn read_version_reply() {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        write_version(&mut server, 2).await.unwrap();
+        let mut buffer = vec![];
+        assert_eq!(read_version(&mut client, &mut buffer).await.unwrap(), 2);
+        assert!(buffer.is_empty());
+
+        write_meta(&mut server, "version:9").await.unwrap();
+        assert!(read_version(&mut client, &mut buffer).await.is_err());
+        write_meta(&mut server, "hello").await.unwrap();
+        assert!(read_version(&mut client, &mut buffer).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_server_sends_cmd() {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        let ping = ProtoCmd::Request(ProtoCmdRequest::new(ProtoCmdBody::Ping));
+        write_cmd(&mut server, &ping).await.unwrap();
+        write_cmd(&mut server, &ping).await.unwrap();
+
+        let mut buffer = vec![];
+        assert_eq!(read_version(&mut client, &mut buffer).await.unwrap(), CMD_VERSION_LEGACY);
+        // 读到的命令不会丢失
+        assert_eq!(read_cmd(&mut client, &mut buffer).await.unwrap(), ping);
+        assert_eq!(read_cmd(&mut client, &mut buffer).await.unwrap(), ping);
+        server.shutdown().await.unwrap();
+        assert!(read_cmd(&mut client, &mut buffer).await.is_err());
+    }
+
+}
@@ -0,0 +1,339 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{HttpHead, MAX_HTTP_HEAD_SIZE};
+
+/// 规则中可以使用的变量
+const HEADER_VARIABLES: [&str; 5] = ["$client_ip", "$url", "$host", "$mapping", "$client"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderScope {
+    /// 转发给后端的请求
+    Request,
+    /// 返回给用户的响应
+    Response,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderOp {
+    /// `+` 追加一个头部
+    Add,
+    /// `=` 替换同名头部
+    Set,
+    /// `-` 删除同名头部
+    Remove,
+}
+
+/// 一条头部改写规则, 配置格式为 `[proxy, +, name, value]`, `[=, name, value]`, `[-, name]`.
+///
+/// 以 `proxy` 开头的规则作用于请求, 否则作用于响应.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderRule {
+    pub scope: HeaderScope,
+    pub op: HeaderOp,
+    pub name: String,
+    pub value: String,
+}
+
+impl HeaderRule {
+    pub fn parse(rule: &[String]) -> Result<HeaderRule, String> {
+        let (scope, rest) = match rule.first().map(|x| x.as_str()) {
+            Some("proxy") => (HeaderScope::Request, &rule[1..]),
+            _ => (HeaderScope::Response, rule),
+        };
+
+        let (op, name, value) = match rest {
+            [op, name] if op == "-" => (HeaderOp::Remove, name, String::new()),
+            [op, name, value] if op == "+" => (HeaderOp::Add, name, value.clone()),
+            [op, name, value] if op == "=" => (HeaderOp::Set, name, value.clone()),
+            _ => return Err(format!("invalid header rule {:?}, expect [proxy,] +|= name value or [proxy,] - name", rule)),
+        };
+
+        if name.is_empty() || !name.bytes().all(|x| x.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&x)) {
+            return Err(format!("invalid header name {:?} in rule {:?}", name, rule));
+        }
+        if value.contains(['\r', '\n']) {
+            return Err(format!("invalid header value in rule {:?}", rule));
+        }
+        if let Some(var) = unknown_variable(&value) {
+            return Err(format!("unknown variable {} in rule {:?}, expect one of {}", var, rule, HEADER_VARIABLES.join(" ")));
+        }
+
+        Ok(HeaderRule { scope, op, name: name.clone(), value })
+    }
+}
+
+/// 以 `$` 开头的字符串中变量名(含 `$`)的长度, 单独的 `$` 长度为 1
+fn variable_len(rest: &str) -> usize {
+    rest[1..].find(|x: char| !(x.is_ascii_alphanumeric() || x == '_')).map(|x| x + 1).unwrap_or(rest.len())
+}
+
+/// 返回值中第一个不支持的变量
+fn unknown_variable(value: &str) -> Option<&str> {
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        rest = &rest[pos..];
+        let len = variable_len(rest);
+        // 单独的 `$` 按普通字符处理
+        if len > 1 && !HEADER_VARIABLES.contains(&&rest[..len]) {
+            return Some(&rest[..len]);
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderRules {
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    pub fn parse(rules: &[Vec<String>]) -> Result<HeaderRules, String> {
+        let rules = rules.iter().map(|x| HeaderRule::parse(x)).collect::<Result<Vec<_>, _>>()?;
+        Ok(HeaderRules { rules })
+    }
+
+    pub fn has_scope(&self, scope: HeaderScope) -> bool {
+        self.rules.iter().any(|x| x.scope == scope)
+    }
+
+    pub fn apply(&self, scope: HeaderScope, head: &mut HttpHead, vars: &HeaderVars) {
+        for rule in self.rules.iter().filter(|x| x.scope == scope) {
+            match rule.op {
+                HeaderOp::Add => head.add(&rule.name, &vars.expand(&rule.value)),
+                HeaderOp::Set => head.set(&rule.name, &vars.expand(&rule.value)),
+                HeaderOp::Remove => head.remove(&rule.name),
+            }
+        }
+    }
+}
+
+/// 规则变量的取值
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderVars {
+    pub client_ip: String,
+    pub url: String,
+    pub host: String,
+    pub mapping: String,
+    pub client: String,
+}
+
+impl HeaderVars {
+    /// 从左到右替换一遍, 变量的值来自用户请求, 不会再次展开
+    pub fn expand(&self, value: &str) -> String {
+        let mut result = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(pos) = rest.find('$') {
+            result.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            let len = variable_len(rest);
+            match &rest[..len] {
+                "$client_ip" => result.push_str(&self.client_ip),
+                "$url" => result.push_str(&self.url),
+                "$host" => result.push_str(&self.host),
+                "$mapping" => result.push_str(&self.mapping),
+                "$client" => result.push_str(&self.client),
+                other => result.push_str(other),
+            }
+            rest = &rest[len..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+enum RewriteState {
+    /// 正在读取响应头
+    Head(Vec<u8>),
+    /// 输出改写后的响应头和已读到的数据, 之后还有响应头时携带剩余数据
+    Output(Vec<u8>, usize, Option<Vec<u8>>),
+    /// 直接透传
+    Body,
+}
+
+/// 读取方向上改写响应头的流, 写入方向直接透传, 因此请求体可以同时发送
+pub struct ResponseHeaderStream<S> {
+    inner: S,
+    state: RewriteState,
+    rules: HeaderRules,
+    vars: HeaderVars,
+}
+
+impl<S> ResponseHeaderStream<S> {
+    pub fn new(inner: S, rules: HeaderRules, vars: HeaderVars) -> Self {
+        Self { inner, state: RewriteState::Head(Vec::new()), rules, vars }
+    }
+
+    fn rewrite(&self, mut buffer: Vec<u8>, pos: usize) -> RewriteState {
+        let rest = buffer.split_off(pos);
+        let Ok(mut head) = HttpHead::parse(&buffer) else {
+            buffer.extend_from_slice(&rest);
+            return RewriteState::Output(buffer, 0, None);
+        };
+
+        // 1xx 临时响应(101 除外)之后还有最终响应
+        let status = head.first_line.split_whitespace().nth(1).and_then(|x| x.parse::<u16>().ok()).unwrap_or(0);
+        if (100..200).contains(&status) && status != 101 {
+            return RewriteState::Output(buffer, 0, Some(rest));
+        }
+
+        self.rules.apply(HeaderScope::Response, &mut head, &self.vars);
+        let mut data = head.encode();
+        data.extend_from_slice(&rest);
+        RewriteState::Output(data, 0, None)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ResponseHeaderStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.state {
+                RewriteState::Head(buffer) => {
+                    if let Some(pos) = buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+                        let buffer = std::mem::take(buffer);
+                        this.state = this.rewrite(buffer, pos + 4);
+                        continue;
+                    }
+
+                    let mut chunk = [0u8; 4096];
+                    let mut chunk_buf = ReadBuf::new(&mut chunk);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+                    let eof = chunk_buf.filled().is_empty();
+                    buffer.extend_from_slice(chunk_buf.filled());
+                    if eof || buffer.len() > MAX_HTTP_HEAD_SIZE {
+                        // 不是完整的响应头, 原样透传
+                        this.state = RewriteState::Output(std::mem::take(buffer), 0, None);
+                    }
+                },
+                RewriteState::Output(data, offset, next) => {
+                    if *offset >= data.len() {
+                        this.state = match next.take() {
+                            Some(buffer) => RewriteState::Head(buffer),
+                            None => RewriteState::Body,
+                        };
+                        continue;
+                    }
+                    let len = buf.remaining().min(data.len() - *offset);
+                    buf.put_slice(&data[*offset..*offset + len]);
+                    *offset += len;
+                    return Poll::Ready(Ok(()));
+                },
+                RewriteState::Body => {
+                    return Pin::new(&mut this.inner).poll_read(cx, buf);
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ResponseHeaderStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(parts: &[&str]) -> Result<HeaderRule, String> {
+        HeaderRule::parse(&parts.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+    }
+
+    fn vars() -> HeaderVars {
+        HeaderVars {
+            client_ip: "10.0.0.1".to_string(),
+            url: "http://a.example.com/x".to_string(),
+            host: "a.example.com".to_string(),
+            mapping: "web".to_string(),
+            client: "client1".to_string(),
+        }
+    }
+
+    #[test]
+    fn expand_variables() {
+        let vars = vars();
+        assert_eq!(vars.expand("plain"), "plain");
+        assert_eq!(vars.expand("$client_ip,$client"), "10.0.0.1,client1");
+        assert_eq!(vars.expand("$mapping@$host$url"), "web@a.example.comhttp://a.example.com/x");
+        assert_eq!(vars.expand("cost $ 5 $"), "cost $ 5 $");
+        assert_eq!(vars.expand("$other-$client"), "$other-client1");
+    }
+
+    #[test]
+    fn expand_does_not_reexpand_values() {
+        let vars = HeaderVars {
+            url: "http://evil/?$client$mapping".to_string(),
+            host: "$client_ip".to_string(),
+            ..vars()
+        };
+        assert_eq!(vars.expand("$url"), "http://evil/?$client$mapping");
+        assert_eq!(vars.expand("$host|$client"), "$client_ip|client1");
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert_eq!(rule(&["proxy", "+", "X-Real-IP", "$client_ip"]).unwrap(), HeaderRule {
+            scope: HeaderScope::Request,
+            op: HeaderOp::Add,
+            name: "X-Real-IP".to_string(),
+            value: "$client_ip".to_string(),
+        });
+        let set = rule(&["=", "Server", "natproxy"]).unwrap();
+        assert_eq!((set.scope, set.op), (HeaderScope::Response, HeaderOp::Set));
+        let remove = rule(&["proxy", "-", "Cookie"]).unwrap();
+        assert_eq!((remove.scope, remove.op, remove.value.as_str()), (HeaderScope::Request, HeaderOp::Remove, ""));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_rules() {
+        assert!(rule(&[]).is_err());
+        assert!(rule(&["proxy"]).is_err());
+        assert!(rule(&["*", "X-A", "1"]).is_err());
+        assert!(rule(&["+", "X-A"]).is_err());
+        assert!(rule(&["-", "X-A", "1"]).is_err());
+        assert!(rule(&["+", "", "1"]).is_err());
+        assert!(rule(&["+", "X A", "1"]).is_err());
+        assert!(rule(&["+", "X-A:", "1"]).is_err());
+        assert!(rule(&["+", "X-A", "1\r\nX-B: 2"]).is_err());
+        assert!(rule(&["+", "X-A", "$unknown"]).is_err());
+        assert!(rule(&["+", "X-A", "$ and $client"]).is_ok());
+    }
+
+    #[test]
+    fn apply_rules_by_scope() {
+        let rules = HeaderRules::parse(&[
+            vec!["proxy".to_string(), "=".to_string(), "Host".to_string(), "$host".to_string()],
+            vec!["proxy".to_string(), "-".to_string(), "Cookie".to_string()],
+            vec!["+".to_string(), "X-Mapping".to_string(), "$mapping".to_string()],
+        ]).unwrap();
+        let mut head = HttpHead::parse(b"GET / HTTP/1.1\r\nHost: old\r\nCookie: a=1\r\ncookie: b=2\r\n\r\n").unwrap();
+        rules.apply(HeaderScope::Request, &mut head, &vars());
+        assert_eq!(head.headers, vec![("Host".to_string(), "a.example.com".to_string())]);
+        assert!(rules.has_scope(HeaderScope::Response));
+    }
+
+    #[tokio::test]
+    async fn response_headers_are_rewritten() {
+        use tokio::io::AsyncReadExt;
+        let rules = HeaderRules::parse(&[vec!["=".to_string(), "Server".to_string(), "$mapping".to_string()]]).unwrap();
+        let data: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nServer: nginx\r\n\r\nbody";
+        let mut stream = ResponseHeaderStream::new(data, rules, vars());
+        let mut output = String::new();
+        stream.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nServer: web\r\n\r\nbody");
+    }
+}
@@ -0,0 +1,299 @@
+use std::io;
+
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use super::check_proxy_credential;
+use crate::utils::{join_host_port, split_host_port};
+
+/// 报文头大小上限, 超过即视为错误请求
+pub const MAX_HTTP_HEAD_SIZE: usize = 64 * 1024;
+
+/// HTTP/1.x 报文头, 按原始顺序保留重复的头部
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpHead {
+    /// 请求行或状态行
+    pub first_line: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpHead {
+    pub fn parse(data: &[u8]) -> io::Result<HttpHead> {
+        let text = std::str::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut lines = text.split("\r\n");
+        let first_line = lines.next().unwrap_or_default().to_string();
+        if first_line.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty http head"));
+        }
+
+        let mut headers = vec![];
+        for line in lines.filter(|x| !x.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid http header: {}", line)));
+            };
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(HttpHead { first_line, headers })
+    }
+
+    /// 拆分请求行为 (方法, 请求目标, 版本)
+    pub fn request_line(&self) -> io::Result<(&str, &str, &str)> {
+        let mut parts = self.first_line.split_whitespace();
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid request line: {}", self.first_line)));
+        };
+        Ok((method, target, version))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// 替换同名头部, 不存在时添加
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.add(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = String::with_capacity(256);
+        data.push_str(&self.first_line);
+        data.push_str("\r\n");
+        for (name, value) in &self.headers {
+            data.push_str(name);
+            data.push_str(": ");
+            data.push_str(value);
+            data.push_str("\r\n");
+        }
+        data.push_str("\r\n");
+        data.into_bytes()
+    }
+}
+
+/// 读取完整的报文头, 返回报文头和随报文头一起读到的报文体数据
+pub async fn read_http_head<R>(reader: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)>
+where R: AsyncRead + Unpin {
+    let mut buffer = Vec::with_capacity(4096);
+    loop {
+        if let Some(pos) = buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+            let rest = buffer.split_off(pos + 4);
+            return Ok((buffer, rest));
+        }
+        if buffer.len() > MAX_HTTP_HEAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "http head too large"));
+        }
+
+        buffer.reserve(4096);
+        if reader.read_buf(&mut buffer).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+    }
+}
+
+/// 正向代理请求
+pub struct HttpProxyRequest {
+    /// 是否 CONNECT 隧道
+    pub connect: bool,
+    /// 目标地址 `host:port`
+    pub target: String,
+    /// 原始请求目标, 如 `http://host/a?b`
+    pub url: String,
+    /// 转发给目标的报文头, CONNECT 时不使用
+    pub head: HttpHead,
+    proxy_auth: Option<String>,
+}
+
+impl HttpProxyRequest {
+    /// 解析正向代理请求, 非 CONNECT 请求的请求行改写为 origin-form 并去掉代理相关头部
+    pub fn parse(data: &[u8]) -> io::Result<HttpProxyRequest> {
+        let mut head = HttpHead::parse(data)?;
+        let (method, url, version) = head.request_line()?;
+        let (method, url, version) = (method.to_string(), url.to_string(), version.to_string());
+        let connect = method.eq_ignore_ascii_case("CONNECT");
+        let target = proxy_target(connect, &url, head.get("Host"))?;
+
+        let proxy_auth = head.get("Proxy-Authorization").map(|x| x.to_string());
+        if !connect {
+            let path = origin_form(&url);
+            head.first_line = format!("{} {} {}", method, path, version);
+            head.remove("Proxy-Authorization");
+            head.remove("Proxy-Connection");
+            // 每个连接只转发一个请求, 避免同一连接上的后续请求发往其它目标
+            head.set("Connection", "close");
+        }
+
+        Ok(HttpProxyRequest { connect, target, url, head, proxy_auth })
+    }
+
+    /// 校验 `Proxy-Authorization: Basic ...`, 认证方案不区分大小写
+    pub fn check_auth(&self, proxy_pass: &str) -> bool {
+        let Some(value) = &self.proxy_auth else {
+            return false;
+        };
+        let Some((scheme, encoded)) = value.split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return false;
+        }
+        let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+            return false;
+        };
+        let (user, pass) = match decoded.iter().position(|x| *x == b':') {
+            Some(pos) => (&decoded[..pos], &decoded[pos + 1..]),
+            None => (&decoded[..], &[][..]),
+        };
+        check_proxy_credential(user, pass, proxy_pass)
+    }
+}
+
+/// 请求目标地址 `host:port`, 支持 `[ipv6]` 形式的主机名
+fn proxy_target(connect: bool, url: &str, host: Option<&str>) -> io::Result<String> {
+    let (authority, default_port) = if connect {
+        (url, 443)
+    } else {
+        match url.split_once("://") {
+            Some((scheme, rest)) => {
+                let end = rest.find(['/', '?']).unwrap_or(rest.len());
+                let port = if scheme.eq_ignore_ascii_case("https") { 443 } else { 80 };
+                (&rest[..end], port)
+            },
+            None => (host.unwrap_or_default(), 80),
+        }
+    };
+    // 去掉 userinfo
+    let authority = authority.rsplit_once('@').map(|x| x.1).unwrap_or(authority);
+    if authority.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "http request without host"));
+    }
+    let (host, port) = split_host_port(authority)?;
+    Ok(join_host_port(&host, port.unwrap_or(default_port)))
+}
+
+/// absolute-form 转为 origin-form, 如 `http://host/a?b` -> `/a?b`
+fn origin_form(path: &str) -> String {
+    match path.split_once("://") {
+        Some((_, rest)) => match rest.find(['/', '?']) {
+            Some(pos) if rest[pos..].starts_with('?') => format!("/{}", &rest[pos..]),
+            Some(pos) => rest[pos..].to_string(),
+            None => String::from("/"),
+        },
+        None => path.to_string(),
+    }
+}
+
+/// 代理自身生成的简单响应
+pub fn http_response(status: u16, reason: &str, headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut head = HttpHead {
+        first_line: format!("HTTP/1.1 {} {}", status, reason),
+        headers: vec![],
+    };
+    for (name, value) in headers {
+        head.add(name, value);
+    }
+    head.add("Content-Length", "0");
+    head.add("Connection", "close");
+    head.encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credential: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(credential)
+    }
+
+    #[test]
+    fn parse_head_keeps_order_and_duplicates() {
+        let head = HttpHead::parse(b"GET / HTTP/1.1\r\nHost: a\r\nSet-Cookie: x=1\r\nset-cookie: y=2\r\n\r\n").unwrap();
+        assert_eq!(head.first_line, "GET / HTTP/1.1");
+        assert_eq!(head.headers.len(), 3);
+        assert_eq!(head.get("SET-COOKIE"), Some("x=1"));
+        assert_eq!(head.encode(), b"GET / HTTP/1.1\r\nHost: a\r\nSet-Cookie: x=1\r\nset-cookie: y=2\r\n\r\n");
+
+        assert!(HttpHead::parse(b"GET / HTTP/1.1\r\nbroken header\r\n\r\n").is_err());
+        assert!(HttpHead::parse(b"\r\n\r\n").is_err());
+        assert!(HttpHead::parse(&[0xff, 0xfe]).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_head_returns_body_prefix() {
+        let mut data: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        let (head, rest) = read_http_head(&mut data).await.unwrap();
+        assert!(head.ends_with(b"\r\n\r\n"));
+        assert_eq!(rest, b"body");
+
+        let mut data: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert_eq!(read_http_head(&mut data).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let large = vec![b'a'; MAX_HTTP_HEAD_SIZE + 8192];
+        assert_eq!(read_http_head(&mut large.as_slice()).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parse_absolute_form_request() {
+        let data = b"GET http://user@example.com:8080/a/b?c=1 HTTP/1.1\r\nHost: example.com:8080\r\n\
+            Proxy-Authorization: Basic eA==\r\nProxy-Connection: keep-alive\r\nConnection: keep-alive\r\n\r\n";
+        let req = HttpProxyRequest::parse(data).unwrap();
+        assert!(!req.connect);
+        assert_eq!(req.target, "example.com:8080");
+        assert_eq!(req.url, "http://user@example.com:8080/a/b?c=1");
+        assert_eq!(req.head.first_line, "GET /a/b?c=1 HTTP/1.1");
+        assert_eq!(req.head.get("Proxy-Authorization"), None);
+        assert_eq!(req.head.get("Proxy-Connection"), None);
+        assert_eq!(req.head.get("Connection"), Some("close"));
+
+        let req = HttpProxyRequest::parse(b"GET http://example.com?q HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.target, "example.com:80");
+        assert_eq!(req.head.first_line, "GET /?q HTTP/1.1");
+
+        let req = HttpProxyRequest::parse(b"GET / HTTP/1.1\r\nHost: [::1]:81\r\n\r\n").unwrap();
+        assert_eq!(req.target, "[::1]:81");
+    }
+
+    #[test]
+    fn parse_connect_request() {
+        let req = HttpProxyRequest::parse(b"CONNECT example.com HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert!(req.connect);
+        assert_eq!(req.target, "example.com:443");
+
+        let req = HttpProxyRequest::parse(b"connect [2001:db8::1]:8443 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.target, "[2001:db8::1]:8443");
+    }
+
+    #[test]
+    fn parse_rejects_bad_request_line() {
+        assert!(HttpProxyRequest::parse(b"GET /\r\n\r\n").is_err());
+        assert!(HttpProxyRequest::parse(b"GET / HTTP/1.1 extra\r\n\r\n").is_err());
+        // origin-form 且没有 Host 时无法确定目标
+        assert!(HttpProxyRequest::parse(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn proxy_auth() {
+        let request = |auth: &str| {
+            let data = format!("CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n", auth);
+            HttpProxyRequest::parse(data.as_bytes()).unwrap()
+        };
+        let credential = basic("user:secret");
+        assert!(request(&format!("Basic {}", credential)).check_auth("user:secret"));
+        assert!(request(&format!("basic {}", credential)).check_auth("user:secret"));
+        assert!(request(&format!("BASIC {}", credential)).check_auth("user:secret"));
+        assert!(!request(&format!("Basic {}", basic("user:wrong"))).check_auth("user:secret"));
+        assert!(!request(&format!("Bearer {}", credential)).check_auth("user:secret"));
+        assert!(!request("Basic not-base64!").check_auth("user:secret"));
+        assert!(!request("Basic").check_auth("user:secret"));
+
+        // 只配置密码时任意用户名均可
+        assert!(request(&format!("Basic {}", basic("anyone:secret"))).check_auth("secret"));
+        assert!(!HttpProxyRequest::parse(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").unwrap().check_auth("secret"));
+    }
+}
@@ -0,0 +1,21 @@
+mod socks5;
+mod http;
+mod reverse;
+mod headers;
+mod sni;
+mod udp;
+
+pub use socks5::*;
+pub use http::*;
+pub use reverse::*;
+pub use headers::*;
+pub use sni::*;
+pub use udp::*;
+
+/// 校验代理认证信息, `proxy_pass` 格式为 `user:password` 时校验用户名和密码, 否则只校验密码
+pub fn check_proxy_credential(user: &[u8], pass: &[u8], proxy_pass: &str) -> bool {
+    match proxy_pass.split_once(':') {
+        Some((expect_user, expect_pass)) => user == expect_user.as_bytes() && pass == expect_pass.as_bytes(),
+        None => pass == proxy_pass.as_bytes(),
+    }
+}
@@ -0,0 +1,108 @@
+use std::io;
+
+use crate::MappingConfig;
+
+use super::HttpHead;
+
+/// 按 Host 和路径前缀选择映射.
+///
+/// 域名相同的映射中路径前缀最长的优先, 没有匹配的域名时使用域名为空的默认映射.
+pub fn match_virtual_host<'a>(mappings: &'a [MappingConfig], host: &str, path: &str) -> Option<&'a MappingConfig> {
+    let matched = |domain: &str| {
+        mappings.iter()
+            .filter(|x| x.domain.eq_ignore_ascii_case(domain) && path_matches(&x.path, path))
+            .max_by_key(|x| x.path.len())
+    };
+
+    if !host.is_empty() {
+        if let Some(mapping) = matched(host) {
+            return Some(mapping);
+        }
+    }
+    matched("")
+}
+
+/// 路径前缀只在路径段的边界上匹配, `/app` 匹配 `/app`, `/app/x` 和 `/app?x`, 不匹配 `/application`
+fn path_matches(prefix: &str, path: &str) -> bool {
+    let Some(rest) = path.strip_prefix(prefix) else {
+        return false;
+    };
+    prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?', '#'])
+}
+
+/// 是否协议升级请求(如 WebSocket): 带有 `Upgrade` 头部且 `Connection` 中包含 upgrade
+pub fn is_upgrade_request(head: &HttpHead) -> bool {
+    head.get("Upgrade").is_some_and(|x| !x.is_empty())
+        && head.get("Connection").is_some_and(|x| x.split(',').any(|x| x.trim().eq_ignore_ascii_case("upgrade")))
+}
+
+/// 反向代理请求的 Host(不含端口) 和请求路径
+pub fn reverse_request_route(head: &HttpHead) -> io::Result<(String, String)> {
+    let (_, path, _) = head.request_line()?;
+
+    let host = head.get("Host").unwrap_or_default();
+    let host = match host.rsplit_once(':') {
+        // IPv6 地址 [::1]:8080
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    Ok((host.to_string(), path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(name: &str, domain: &str, path: &str) -> MappingConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "mode": "httpreverse",
+            "listen": "127.0.0.1:8080",
+            "forward": "127.0.0.1:80",
+            "domain": domain,
+            "path": path,
+        })).unwrap()
+    }
+
+    fn matched<'a>(mappings: &'a [MappingConfig], host: &str, path: &str) -> Option<&'a str> {
+        match_virtual_host(mappings, host, path).map(|x| x.name.as_str())
+    }
+
+    #[test]
+    fn path_prefix_matches_on_segment_boundary() {
+        let mappings = [mapping("root", "", ""), mapping("app", "", "/app"), mapping("api", "", "/api/")];
+        assert_eq!(matched(&mappings, "", "/app"), Some("app"));
+        assert_eq!(matched(&mappings, "", "/app/index.html"), Some("app"));
+        assert_eq!(matched(&mappings, "", "/app?x=1"), Some("app"));
+        assert_eq!(matched(&mappings, "", "/application"), Some("root"));
+        assert_eq!(matched(&mappings, "", "/api/v1"), Some("api"));
+        assert_eq!(matched(&mappings, "", "/api"), Some("root"));
+    }
+
+    #[test]
+    fn host_routes_before_default() {
+        let mappings = [mapping("default", "", "/"), mapping("a", "a.example.com", "/"), mapping("a-app", "A.example.com", "/app")];
+        assert_eq!(matched(&mappings, "a.example.com", "/app/x"), Some("a-app"));
+        assert_eq!(matched(&mappings, "a.example.com", "/other"), Some("a"));
+        assert_eq!(matched(&mappings, "b.example.com", "/app"), Some("default"));
+        assert_eq!(matched(&mappings[1..], "b.example.com", "/app"), None);
+    }
+
+    #[test]
+    fn request_route_strips_port() {
+        let head = HttpHead::parse(b"GET /a HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").unwrap();
+        assert_eq!(reverse_request_route(&head).unwrap(), ("example.com".to_string(), "/a".to_string()));
+        let head = HttpHead::parse(b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n").unwrap();
+        assert_eq!(reverse_request_route(&head).unwrap().0, "[::1]");
+    }
+
+    #[test]
+    fn upgrade_request() {
+        let head = HttpHead::parse(b"GET /ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n").unwrap();
+        assert!(is_upgrade_request(&head));
+        let head = HttpHead::parse(b"GET /ws HTTP/1.1\r\nConnection: keep-alive\r\nUpgrade: websocket\r\n\r\n").unwrap();
+        assert!(!is_upgrade_request(&head));
+        let head = HttpHead::parse(b"GET / HTTP/1.1\r\nConnection: upgrade\r\n\r\n").unwrap();
+        assert!(!is_upgrade_request(&head));
+    }
+}
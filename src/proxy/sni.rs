@@ -0,0 +1,219 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const TLS_RECORD_HEADER_SIZE: usize = 5;
+const TLS_CONTENT_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_TYPE_HOST: u8 = 0x00;
+/// ClientHello 大小上限, 超过即视为错误
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+/// 读取 TLS ClientHello, 不终止 TLS.
+///
+/// 返回已读取的原始数据(需要原样转发给后端)和其中的 SNI 域名.
+pub async fn read_client_hello<R>(reader: &mut R) -> io::Result<(Vec<u8>, Option<String>)>
+where R: AsyncRead + Unpin {
+    let mut raw = Vec::new();
+    let mut handshake = Vec::new();
+    loop {
+        let mut header = [0u8; TLS_RECORD_HEADER_SIZE];
+        reader.read_exact(&mut header).await?;
+        if header[0] != TLS_CONTENT_HANDSHAKE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a tls handshake"));
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload).await?;
+        raw.extend_from_slice(&header);
+        raw.extend_from_slice(&payload);
+        handshake.extend_from_slice(&payload);
+
+        // ClientHello 可能被拆分到多个记录中
+        if handshake.len() >= 4 {
+            if handshake[0] != TLS_HANDSHAKE_CLIENT_HELLO {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a tls client hello"));
+            }
+            let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + hello_len {
+                let sni = parse_server_name(&handshake[4..4 + hello_len]);
+                return Ok((raw, sni));
+            }
+        }
+        if raw.len() > MAX_CLIENT_HELLO_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "tls client hello too large"));
+        }
+    }
+}
+
+/// 从 ClientHello 消息体中解析 server_name 扩展
+fn parse_server_name(hello: &[u8]) -> Option<String> {
+    let mut reader = ByteReader { data: hello, pos: 0 };
+    // client_version(2) + random(32)
+    reader.skip(34)?;
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = ByteReader { data: reader.take(extensions_len)?, pos: 0 };
+    while let (Some(ext_type), Some(ext_len)) = (extensions.u16(), extensions.u16()) {
+        let ext_data = extensions.take(ext_len as usize)?;
+        if ext_type != TLS_EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = ByteReader { data: ext_data, pos: 0 };
+        let list_len = names.u16()? as usize;
+        let mut list = ByteReader { data: names.take(list_len)?, pos: 0 };
+        while let Some(name_type) = list.u8() {
+            let name_len = list.u16()? as usize;
+            let name = list.take(name_len)?;
+            if name_type == SERVER_NAME_TYPE_HOST {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let value = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(value)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造 ClientHello 消息(含握手头), `sni` 为空时不带 server_name 扩展
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([7u8; 32]);
+        body.push(0);
+        body.extend([0x00, 0x02, 0x13, 0x01]);
+        body.extend([0x01, 0x00]);
+
+        let mut extensions = vec![];
+        // supported_versions 扩展在 server_name 之前, 确保会跳过其它扩展
+        extensions.extend([0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(sni) = sni {
+            let name = sni.as_bytes();
+            let list_len = name.len() as u16 + 3;
+            extensions.extend([0x00, 0x00]);
+            extensions.extend((list_len + 2).to_be_bytes());
+            extensions.extend(list_len.to_be_bytes());
+            extensions.push(SERVER_NAME_TYPE_HOST);
+            extensions.extend((name.len() as u16).to_be_bytes());
+            extensions.extend(name);
+        }
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut hello = vec![TLS_HANDSHAKE_CLIENT_HELLO];
+        hello.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend(body);
+        hello
+    }
+
+    fn record(payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![TLS_CONTENT_HANDSHAKE, 0x03, 0x01];
+        data.extend((payload.len() as u16).to_be_bytes());
+        data.extend(payload);
+        data
+    }
+
+    #[tokio::test]
+    async fn read_sni() {
+        let data = record(&client_hello(Some("a.example.com")));
+        let (raw, sni) = read_client_hello(&mut data.as_slice()).await.unwrap();
+        assert_eq!(raw, data);
+        assert_eq!(sni.as_deref(), Some("a.example.com"));
+    }
+
+    #[tokio::test]
+    async fn hello_without_sni() {
+        let data = record(&client_hello(None));
+        let (raw, sni) = read_client_hello(&mut data.as_slice()).await.unwrap();
+        assert_eq!(raw, data);
+        assert_eq!(sni, None);
+    }
+
+    #[tokio::test]
+    async fn hello_split_across_records() {
+        let hello = client_hello(Some("b.example.com"));
+        let (first, second) = hello.split_at(3);
+        let mut data = record(first);
+        data.extend(record(second));
+        data.extend(b"application data");
+        let (raw, sni) = read_client_hello(&mut data.as_slice()).await.unwrap();
+        assert_eq!(raw, data[..data.len() - 16]);
+        assert_eq!(sni.as_deref(), Some("b.example.com"));
+    }
+
+    #[tokio::test]
+    async fn truncated_hello() {
+        // 连接在记录中途关闭
+        let data = record(&client_hello(Some("a.example.com")));
+        let err = read_client_hello(&mut &data[..data.len() - 4]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // 消息长度正确但内部字段越界
+        let mut hello = client_hello(Some("a.example.com"));
+        let len = hello.len();
+        hello.truncate(len - 6);
+        hello[1..4].copy_from_slice(&((len - 10) as u32).to_be_bytes()[1..]);
+        let (_, sni) = read_client_hello(&mut record(&hello).as_slice()).await.unwrap();
+        assert_eq!(sni, None);
+        assert_eq!(parse_server_name(&[0x03, 0x03, 1, 2]), None);
+    }
+
+    #[tokio::test]
+    async fn not_a_client_hello() {
+        let err = read_client_hello(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut hello = client_hello(None);
+        hello[0] = 0x02;
+        let err = read_client_hello(&mut record(&hello).as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn oversized_hello() {
+        let mut hello = vec![TLS_HANDSHAKE_CLIENT_HELLO, 0xff, 0xff, 0xff];
+        hello.resize(u16::MAX as usize, 0);
+        let mut data = vec![];
+        while data.len() <= MAX_CLIENT_HELLO_SIZE {
+            data.extend(record(&hello));
+            hello = vec![0; u16::MAX as usize];
+        }
+        let err = read_client_hello(&mut data.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,211 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::check_proxy_credential;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const SOCKS5_REP_SUCCEEDED: u8 = 0x00;
+pub const SOCKS5_REP_HOST_UNREACHABLE: u8 = 0x04;
+pub const SOCKS5_REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const SOCKS5_REP_TTL_EXPIRED: u8 = 0x06;
+pub const SOCKS5_REP_CMD_NOT_SUPPORTED: u8 = 0x07;
+pub const SOCKS5_REP_ATYP_NOT_SUPPORTED: u8 = 0x08;
+
+/// 完成 SOCKS5 认证和 CONNECT 请求解析, 返回目标地址 `host:port`.
+///
+/// `proxy_pass` 为空时不需要认证. 失败时已向客户端回复错误.
+pub async fn socks5_handshake<S>(stream: &mut S, proxy_pass: Option<&str>) -> io::Result<String>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let version = stream.read_u8().await?;
+    if version != SOCKS5_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported socks version {}", version)));
+    }
+    let nmethods = stream.read_u8().await? as usize;
+    let mut methods = vec![0u8; nmethods];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if proxy_pass.is_some() { METHOD_USER_PASS } else { METHOD_NO_AUTH };
+    if !methods.contains(&method) {
+        stream.write_all(&[SOCKS5_VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "no acceptable socks auth method"));
+    }
+    stream.write_all(&[SOCKS5_VERSION, method]).await?;
+
+    if let Some(proxy_pass) = proxy_pass {
+        socks5_auth(stream, proxy_pass).await?;
+    }
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported socks version {}", header[0])));
+    }
+
+    let host = match header[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        },
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            format!("[{}]", Ipv6Addr::from(addr))
+        },
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        },
+        atyp => {
+            socks5_reply(stream, SOCKS5_REP_ATYP_NOT_SUPPORTED).await?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported socks address type {}", atyp)));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if header[1] != CMD_CONNECT {
+        socks5_reply(stream, SOCKS5_REP_CMD_NOT_SUPPORTED).await?;
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("unsupported socks command {}", header[1])));
+    }
+
+    Ok(format!("{}:{}", host, port))
+}
+
+async fn socks5_auth<S>(stream: &mut S, proxy_pass: &str) -> io::Result<()>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let version = stream.read_u8().await?;
+    if version != AUTH_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported socks auth version {}", version)));
+    }
+    let ulen = stream.read_u8().await? as usize;
+    let mut user = vec![0u8; ulen];
+    stream.read_exact(&mut user).await?;
+    let plen = stream.read_u8().await? as usize;
+    let mut pass = vec![0u8; plen];
+    stream.read_exact(&mut pass).await?;
+
+    let passed = check_proxy_credential(&user, &pass, proxy_pass);
+    stream.write_all(&[AUTH_VERSION, if passed { 0x00 } else { 0x01 }]).await?;
+    if !passed {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "socks auth failed"));
+    }
+    Ok(())
+}
+
+/// 回复 CONNECT 结果, 绑定地址固定为 0.0.0.0:0, 出站连接实际由客户端发起
+pub async fn socks5_reply<S>(stream: &mut S, rep: u8) -> io::Result<()>
+where S: AsyncWrite + Unpin {
+    stream.write_all(&[SOCKS5_VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// 把客户端发送的字节写入管道后完成握手, 返回握手结果和服务端回复的字节
+    async fn handshake(input: &[u8], proxy_pass: Option<&str>) -> (io::Result<String>, Vec<u8>) {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let result = socks5_handshake(&mut server, proxy_pass).await;
+        drop(server);
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        (result, reply)
+    }
+
+    #[tokio::test]
+    async fn connect_ipv4() {
+        let (result, reply) = handshake(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0x1f, 0x90], None).await;
+        assert_eq!(result.unwrap(), "10.0.0.1:8080");
+        assert_eq!(reply, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn connect_domain() {
+        let mut input = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        input.extend(b"example.com");
+        input.extend(443u16.to_be_bytes());
+        let (result, _) = handshake(&input, None).await;
+        assert_eq!(result.unwrap(), "example.com:443");
+    }
+
+    #[tokio::test]
+    async fn connect_ipv6() {
+        let mut input = vec![5, 1, 0, 5, 1, 0, 4];
+        input.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        input.extend(22u16.to_be_bytes());
+        let (result, _) = handshake(&input, None).await;
+        assert_eq!(result.unwrap(), "[2001:db8::1]:22");
+    }
+
+    #[tokio::test]
+    async fn unsupported_command_is_rejected() {
+        // BIND 和 UDP ASSOCIATE 不支持
+        for cmd in [2, 3] {
+            let (result, reply) = handshake(&[5, 1, 0, 5, cmd, 0, 1, 127, 0, 0, 1, 0, 80], None).await;
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+            assert_eq!(reply[2..4], [5, SOCKS5_REP_CMD_NOT_SUPPORTED]);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_address_type_is_rejected() {
+        let (result, reply) = handshake(&[5, 1, 0, 5, 1, 0, 9, 0, 0], None).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(reply[2..4], [5, SOCKS5_REP_ATYP_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn bad_version_and_truncated_request() {
+        let (result, reply) = handshake(&[4, 1, 0], None).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(reply.is_empty());
+
+        let (result, _) = handshake(&[5, 1, 0, 5, 1, 0, 3, 20, b'a'], None).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn auth_method_and_credentials() {
+        // 需要密码时不接受免认证
+        let (result, reply) = handshake(&[5, 1, 0], Some("user:secret")).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(reply, [5, METHOD_NOT_ACCEPTABLE]);
+
+        let mut input = vec![5, 1, 2, 1, 4];
+        input.extend(b"user");
+        input.push(6);
+        input.extend(b"secret");
+        input.extend([5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+        let (result, reply) = handshake(&input, Some("user:secret")).await;
+        assert_eq!(result.unwrap(), "127.0.0.1:80");
+        assert_eq!(reply, [5, 2, 1, 0]);
+
+        let mut input = vec![5, 1, 2, 1, 4];
+        input.extend(b"user");
+        input.push(5);
+        input.extend(b"wrong");
+        let (result, reply) = handshake(&input, Some("user:secret")).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(reply, [5, 2, 1, 1]);
+    }
+}
@@ -0,0 +1,159 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::time::{sleep, Duration};
+
+use crate::resolve_addrs;
+
+/// 数据报在流上的编码: length(2) + payload
+pub const DATAGRAM_HEADER_SIZE: usize = 2;
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+/// UDP 会话空闲超时, 超时后关闭转发通道
+pub const UDP_SESSION_IDLE_TIMEOUT: u64 = 60;
+
+pub async fn write_datagram<W>(writer: &mut W, data: &[u8]) -> io::Result<()>
+where W: AsyncWrite + Unpin {
+    if data.len() > MAX_DATAGRAM_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "datagram too large"));
+    }
+    let mut buf = Vec::with_capacity(DATAGRAM_HEADER_SIZE + data.len());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    writer.write_all(&buf).await?;
+    writer.flush().await
+}
+
+/// 读取一个数据报, 流结束时返回 `None`.
+///
+/// 未解析完的数据保留在 `buffer` 中, 可以在 `select!` 中使用.
+pub async fn read_datagram<R>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>>
+where R: AsyncRead + Unpin {
+    loop {
+        if buffer.len() >= DATAGRAM_HEADER_SIZE {
+            let length = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+            if buffer.len() >= DATAGRAM_HEADER_SIZE + length {
+                let data = buffer[DATAGRAM_HEADER_SIZE..DATAGRAM_HEADER_SIZE + length].to_vec();
+                buffer.drain(..DATAGRAM_HEADER_SIZE + length);
+                return Ok(Some(data));
+            }
+        }
+
+        buffer.reserve(4096);
+        if reader.read_buf(buffer).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// 连接 UDP 目标地址, 依次尝试解析出的每个地址, 按地址族绑定本地端口
+pub async fn connect_udp(target: &str) -> io::Result<UdpSocket> {
+    let mut last_err = None;
+    for addr in resolve_addrs(target, true).await? {
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = match UdpSocket::bind(local).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                last_err = Some(e);
+                continue;
+            }
+        };
+        match socket.connect(addr).await {
+            Ok(_) => return Ok(socket),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", target))))
+}
+
+/// 在流和已连接的 UDP 套接字之间转发数据报, 流关闭或空闲超时后结束
+pub async fn udp_stream_relay<S>(stream: &mut S, socket: UdpSocket) -> io::Result<()>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let mut buffer = Vec::new();
+    let mut recv_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        select! {
+            data = read_datagram(stream, &mut buffer) => {
+                let Some(data) = data? else {
+                    return Ok(());
+                };
+                socket.send(&data).await?;
+            },
+            len = socket.recv(&mut recv_buf) => {
+                match len {
+                    Ok(len) => write_datagram(stream, &recv_buf[..len]).await?,
+                    // 目标端口不可达时会收到 ICMP 错误, 忽略并继续等待
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                    Err(e) => return Err(e),
+                }
+            },
+            _ = sleep(Duration::from_secs(UDP_SESSION_IDLE_TIMEOUT)) => {
+                log::debug!("udp session idle timeout");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn datagram_round_trip() {
+        let mut data = Vec::new();
+        write_datagram(&mut data, b"hello").await.unwrap();
+        write_datagram(&mut data, b"").await.unwrap();
+        write_datagram(&mut data, &[7u8; MAX_DATAGRAM_SIZE]).await.unwrap();
+        assert_eq!(&data[..DATAGRAM_HEADER_SIZE + 5], b"\x00\x05hello");
+
+        let mut reader = data.as_slice();
+        let mut buffer = Vec::new();
+        assert_eq!(read_datagram(&mut reader, &mut buffer).await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_datagram(&mut reader, &mut buffer).await.unwrap(), Some(vec![]));
+        assert_eq!(read_datagram(&mut reader, &mut buffer).await.unwrap(), Some(vec![7u8; MAX_DATAGRAM_SIZE]));
+        assert_eq!(read_datagram(&mut reader, &mut buffer).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_datagram_is_rejected() {
+        let mut data = Vec::new();
+        let err = write_datagram(&mut data, &vec![0u8; MAX_DATAGRAM_SIZE + 1]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_datagram_across_partial_reads() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut data = Vec::new();
+        write_datagram(&mut data, b"first").await.unwrap();
+        write_datagram(&mut data, b"second").await.unwrap();
+
+        let writer = tokio::spawn(async move {
+            for chunk in data.chunks(3) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut buffer = Vec::new();
+        assert_eq!(read_datagram(&mut server, &mut buffer).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_datagram(&mut server, &mut buffer).await.unwrap(), Some(b"second".to_vec()));
+        writer.await.unwrap();
+        assert_eq!(read_datagram(&mut server, &mut buffer).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn connect_udp_by_family() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = connect_udp(&peer.local_addr().unwrap().to_string()).await.unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv4());
+        socket.send(b"ping").await.unwrap();
+        let mut buf = [0u8; 8];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from.port(), socket.local_addr().unwrap().port());
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs;
+use std::time::SystemTime;
+
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::utils::{new_tls_acceptor, new_tls_connector};
+use crate::{AppOption, AppResult};
+
+/// 检查配置文件是否修改的间隔秒数
+const CONFIG_WATCH_INTERVAL: u64 = 2;
+
+/// 开始监听配置重新加载: 收到 SIGHUP, 或开启 `config_watch` 后配置文件被修改时重新读取 `-c` 指定的文件.
+/// 新配置校验通过后立即应用日志级别, 再通过返回的通道通知服务端和客户端, 无效的配置被忽略
+pub fn start_config_reload(option: &AppOption) -> watch::Receiver<AppOption> {
+    let (reload_tx, reload_rx) = watch::channel(option.clone());
+    let mut current = option.clone();
+    tokio::spawn(async move {
+        let mut hangup = hangup_signal();
+        let mut ticker = interval(Duration::from_secs(CONFIG_WATCH_INTERVAL));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut modified = current.config.as_deref().and_then(config_modified);
+        loop {
+            select! {
+                _ = wait_hangup(&mut hangup) => {
+                    log::info!("received SIGHUP, reload config");
+                },
+                _ = ticker.tick(), if current.config_watch && current.config.is_some() => {
+                    let latest = current.config.as_deref().and_then(config_modified);
+                    if latest == modified {
+                        continue;
+                    }
+                    modified = latest;
+                    log::info!("config file changed, reload config");
+                },
+                _ = reload_tx.closed() => {
+                    break;
+                }
+            }
+
+            let Some(config) = current.config.clone() else {
+                log::warn!("no config file specified by -c, nothing to reload");
+                continue;
+            };
+            match reload_config(&config, &current) {
+                Ok(option) => {
+                    modified = config_modified(&config);
+                    if option == current {
+                        log::info!("config is not changed");
+                        continue;
+                    }
+                    if option.log_level != current.log_level {
+                        log::info!("log level changed to {}", option.log_level);
+                        log::set_max_level(option.log_level_filter());
+                    }
+                    let restart = restart_required(&current, &option);
+                    if !restart.is_empty() {
+                        log::warn!("changes of {} take effect after restart", restart.join(", "));
+                    }
+                    log::info!("config reloaded from {}", config);
+                    current = option.clone();
+                    reload_tx.send_replace(option);
+                },
+                Err(e) => {
+                    log::error!("reload config failed, keep the previous config: {:?}", e);
+                }
+            }
+        }
+    });
+    reload_rx
+}
+
+/// 读取并校验新配置, 包括证书文件
+fn reload_config(config: &str, current: &AppOption) -> AppResult<AppOption> {
+    let option = AppOption::load_config(config)?;
+    option.validate()?;
+    if option.role != current.role {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "role cannot be changed by reload").into());
+    }
+    if let (Some(ca_file), Some(cert_file), Some(key_file)) = (&option.ca_cert, &option.cert, &option.key) {
+        if option.role == "server" {
+            new_tls_acceptor(ca_file, cert_file, key_file)?;
+        } else {
+            new_tls_connector(ca_file, cert_file, key_file)?;
+        }
+    }
+    Ok(option)
+}
+
+/// 不能在运行中应用的配置项.
+/// 服务端可以应用映射, 日志级别和证书; 客户端重新声明映射, 其它连接相关的配置重新连接后生效
+fn restart_required(old: &AppOption, new: &AppOption) -> Vec<&'static str> {
+    let changes = [
+        ("admin_listen", old.admin_listen != new.admin_listen),
+        ("admin_token", old.admin_token != new.admin_token),
+        ("prefer_ip", old.prefer_ip != new.prefer_ip),
+    ];
+    let mut fields: Vec<&'static str> = changes.iter().filter(|x| x.1).map(|x| x.0).collect();
+    if old.role != "server" {
+        let reconnect = old.reconnect_delay != new.reconnect_delay || old.reconnect_max_delay != new.reconnect_max_delay
+            || old.reconnect_multiplier != new.reconnect_multiplier || old.reconnect_jitter != new.reconnect_jitter
+            || old.reconnect_max_attempts != new.reconnect_max_attempts;
+        if reconnect {
+            fields.push("reconnect policy");
+        }
+        return fields;
+    }
+    let changes = [
+        ("listen", old.listen != new.listen),
+        ("signal_port", old.signal_port != new.signal_port),
+        ("data_port", old.data_port != new.data_port),
+        ("connect_mode", old.connect_mode != new.connect_mode),
+        ("client_addrs", old.client_addrs != new.client_addrs),
+        ("mux", old.mux != new.mux || old.mux_channels != new.mux_channels),
+        ("heartbeat", old.heartbeat_interval != new.heartbeat_interval || old.heartbeat_timeout != new.heartbeat_timeout),
+        ("proxy_on", old.proxy_on != new.proxy_on),
+        ("proxy_pass", old.proxy_pass != new.proxy_pass),
+        ("client_policies", old.client_policies != new.client_policies),
+    ];
+    fields.extend(changes.iter().filter(|x| x.1).map(|x| x.0));
+    fields
+}
+
+/// 客户端连接相关的配置变化需要重新连接服务端, 映射和日志级别直接应用, 需要重启的配置项不触发重连
+pub fn reconnect_required(old: &AppOption, new: &AppOption) -> bool {
+    let mut new = new.clone();
+    new.mappings = old.mappings.clone();
+    new.log_level = old.log_level.clone();
+    new.config_watch = old.config_watch;
+    new.admin_listen = old.admin_listen;
+    new.admin_token = old.admin_token.clone();
+    new.prefer_ip = old.prefer_ip.clone();
+    new.reconnect_delay = old.reconnect_delay;
+    new.reconnect_max_delay = old.reconnect_max_delay;
+    new.reconnect_multiplier = old.reconnect_multiplier;
+    new.reconnect_jitter = old.reconnect_jitter;
+    new.reconnect_max_attempts = old.reconnect_max_attempts;
+    new != *old
+}
+
+fn config_modified(config: &str) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(config).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup()).map_err(|e| log::error!("cannot listen SIGHUP: {}", e)).ok()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {}
+
+#[cfg(unix)]
+async fn wait_hangup(hangup: &mut Hangup) {
+    if let Some(signal) = hangup {
+        if signal.recv().await.is_some() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+#[cfg(not(unix))]
+async fn wait_hangup(_hangup: &mut Hangup) {
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingConfig;
+
+    fn client() -> AppOption {
+        AppOption { role: String::from("client"), server: Some(String::from("127.0.0.1:8001")), ..Default::default() }
+    }
+
+    #[test]
+    fn server_restart_fields() {
+        let old = AppOption::default();
+        assert!(restart_required(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.mappings.push(MappingConfig::new(String::from("tcp"), String::from("tcp"), String::new(), String::from("127.0.0.1:80"), vec![]));
+        new.log_level = String::from("debug");
+        assert!(restart_required(&old, &new).is_empty());
+
+        new.signal_port += 1;
+        new.heartbeat_interval += 1;
+        new.admin_token = Some(String::from("token"));
+        assert_eq!(restart_required(&old, &new), vec!["admin_token", "signal_port", "heartbeat"]);
+    }
+
+    #[test]
+    fn client_restart_fields() {
+        let old = client();
+        let mut new = old.clone();
+        new.signal_port += 1;
+        new.proxy_pass = Some(String::from("secret"));
+        assert!(restart_required(&old, &new).is_empty());
+
+        new.reconnect_delay += 1;
+        new.prefer_ip = String::from("ipv6");
+        assert_eq!(restart_required(&old, &new), vec!["prefer_ip", "reconnect policy"]);
+    }
+
+    #[test]
+    fn client_reconnect_on_connection_change() {
+        let old = client();
+        let mut new = old.clone();
+        new.mappings.push(MappingConfig::new(String::from("tcp"), String::from("tcp"), String::new(), String::from("127.0.0.1:80"), vec![]));
+        new.log_level = String::from("debug");
+        new.reconnect_delay += 1;
+        new.admin_listen = Some("127.0.0.1:9100".parse().unwrap());
+        assert!(!reconnect_required(&old, &new));
+
+        new.server = Some(String::from("127.0.0.1:9001"));
+        assert!(reconnect_required(&old, &new));
+
+        let mut new = old.clone();
+        new.heartbeat_interval += 1;
+        assert!(reconnect_required(&old, &new));
+    }
+}
@@ -0,0 +1,121 @@
+use serde_json::json;
+
+use crate::admin::{admin_body, AdminFuture, AdminHandler, AdminResponse, MetricsWriter};
+use crate::MappingConfig;
+
+use super::{ClientHandle, ServerContext};
+
+/// 服务端管理接口
+///
+/// - `GET /clients`, `GET /clients/{name}`: 已连接的客户端
+/// - `DELETE /clients/{name}`: 断开客户端
+/// - `GET /mappings`: 映射及其监听状态
+/// - `POST /mappings`: 新增映射, 请求体为映射配置
+/// - `PUT /mappings/{name}`: 修改映射
+/// - `DELETE /mappings/{name}`: 移除映射, 已建立的会话继续转发一段时间后关闭
+/// - `GET /sessions`: 正在转发的会话
+/// - `DELETE /sessions/{bind_id}`: 关闭会话
+/// - `GET /metrics`: Prometheus 格式的监控指标
+///
+/// 修改的只是配置文件中的映射, 客户端声明的映射由客户端管理
+pub(super) struct ServerAdmin {
+    pub(super) ctx: ServerContext,
+}
+
+fn server_metrics(ctx: &ServerContext) -> String {
+    let mut writer = MetricsWriter::new();
+    writer.metric("natproxy_clients_connected", "gauge", "Clients connected to the server.", &[(vec![], ctx.registry.list().len() as u64)]);
+    writer.traffic(&ctx.sessions.traffic());
+    let bind_timeouts = ctx.metrics.bind_timeouts();
+    let samples: Vec<(Vec<(&str, &str)>, u64)> = bind_timeouts.iter().map(|(mapping, count)| (vec![("mapping", mapping.as_str())], *count)).collect();
+    writer.metric("natproxy_bind_timeouts_total", "counter", "Forward connections not bound by the client in time.", &samples);
+    writer.metric("natproxy_tls_handshake_failures_total", "counter", "Failed TLS handshakes with clients.", &[(vec![], ctx.metrics.tls_handshake_failures())]);
+    writer.finish()
+}
+
+fn client_info(handle: &ClientHandle, mux_channels: usize) -> serde_json::Value {
+    json!({
+        "name": handle.name,
+        "client_id": handle.client_id,
+        "peer_addr": handle.peer_addr.to_string(),
+        "version": handle.version,
+        "connected_at": handle.connected_at,
+        "passive_addr": handle.dial_addr,
+        "mux_channels": mux_channels,
+        "certificate": handle.identity,
+    })
+}
+
+impl AdminHandler for ServerAdmin {
+    fn handle<'a>(&'a self, method: &'a str, path: &'a [&'a str], body: &'a [u8]) -> AdminFuture<'a> {
+        Box::pin(async move {
+            let ctx = &self.ctx;
+            match (method, path) {
+                ("GET", ["clients"]) => {
+                    let clients: Vec<serde_json::Value> = ctx.registry.list().iter().map(|(handle, mux)| client_info(handle, *mux)).collect();
+                    AdminResponse::ok(clients)
+                },
+                ("GET", ["clients", name]) => {
+                    match ctx.registry.list().iter().find(|(handle, _)| handle.name == *name) {
+                        Some((handle, mux)) => AdminResponse::ok(client_info(handle, *mux)),
+                        None => AdminResponse::not_found(),
+                    }
+                },
+                ("DELETE", ["clients", name]) => {
+                    if ctx.registry.disconnect(name) {
+                        AdminResponse::ok(json!({ "disconnected": name }))
+                    } else {
+                        AdminResponse::not_found()
+                    }
+                },
+                ("GET", ["mappings"]) => AdminResponse::ok(ctx.listeners.list()),
+                ("POST", ["mappings"]) => {
+                    let mapping: MappingConfig = match admin_body(body) {
+                        Ok(mapping) => mapping,
+                        Err(rsp) => return rsp,
+                    };
+                    if ctx.listeners.get(&mapping.name, None).is_some() {
+                        return AdminResponse::error(409, &format!("mapping {} already exists", mapping.name));
+                    }
+                    match ctx.add_mapping(&mapping).await {
+                        Ok(_) => AdminResponse::ok(json!({ "added": mapping.name })),
+                        Err(e) => AdminResponse::error(400, &e),
+                    }
+                },
+                ("PUT", ["mappings", name]) => {
+                    let mapping: MappingConfig = match admin_body(body) {
+                        Ok(mapping) => mapping,
+                        Err(rsp) => return rsp,
+                    };
+                    if mapping.name != *name {
+                        return AdminResponse::error(400, "mapping name does not match the path");
+                    }
+                    if ctx.listeners.get(name, None).is_none() {
+                        return AdminResponse::not_found();
+                    }
+                    match ctx.update_mapping(&mapping).await {
+                        Ok(_) => AdminResponse::ok(json!({ "updated": name })),
+                        Err(e) => AdminResponse::error(400, &e),
+                    }
+                },
+                ("DELETE", ["mappings", name]) => {
+                    match ctx.remove_mapping(name) {
+                        Some(_) => AdminResponse::ok(json!({ "removed": name })),
+                        None => AdminResponse::not_found(),
+                    }
+                },
+                ("GET", ["sessions"]) => AdminResponse::ok(ctx.sessions.list()),
+                ("DELETE", ["sessions", bind_id]) => {
+                    if ctx.sessions.close(bind_id) {
+                        AdminResponse::ok(json!({ "closed": bind_id }))
+                    } else {
+                        AdminResponse::not_found()
+                    }
+                },
+                ("GET", ["metrics"]) => AdminResponse::text(server_metrics(ctx)),
+                (_, ["clients"] | ["clients", _] | ["mappings"] | ["mappings", _] | ["sessions"] | ["sessions", _] | ["metrics"]) => AdminResponse::error(405, "method not allowed"),
+                _ => AdminResponse::not_found(),
+            }
+        })
+    }
+}
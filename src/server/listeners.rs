@@ -0,0 +1,305 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::MappingConfig;
+
+/// 监听任务读取的映射列表, 修改后对新连接立即生效
+pub type SharedMappings = Arc<Mutex<Vec<MappingConfig>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerState {
+    Listening,
+    /// 模式未启用或配置不完整, 没有监听
+    Skipped,
+    /// 监听失败
+    Failed,
+}
+
+/// 映射的监听状态
+#[derive(Clone, Debug, Serialize)]
+pub struct ListenerInfo {
+    #[serde(flatten)]
+    pub mapping: MappingConfig,
+    /// 声明该映射的客户端, 配置文件中的映射为空
+    pub owner: Option<String>,
+    pub state: ListenerState,
+    pub reason: Option<String>,
+}
+
+/// 共享同一监听地址的一组映射
+struct ListenerGroup {
+    listen: SocketAddr,
+    udp: bool,
+    owner: Option<String>,
+    mappings: SharedMappings,
+    /// 单独停止该监听, 释放后监听同样停止
+    stop: watch::Sender<String>,
+}
+
+impl ListenerGroup {
+    fn accepts(&self, mapping: &MappingConfig, owner: Option<&str>) -> bool {
+        self.listen == mapping.listen.unwrap_or(self.listen) && self.udp == mapping.is_udp() && self.owner.as_deref() == owner
+    }
+}
+
+/// 所有映射的监听, 按监听地址分组
+#[derive(Clone, Default)]
+pub struct ProxyListeners {
+    groups: Arc<Mutex<Vec<ListenerGroup>>>,
+    /// 没有监听的映射及原因
+    inactive: Arc<Mutex<Vec<ListenerInfo>>>,
+}
+
+impl ProxyListeners {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 加入同一地址上已有的虚拟主机监听, 没有可加入的监听时返回 false
+    pub fn join_group(&self, mapping: &MappingConfig, owner: Option<&str>) -> bool {
+        let groups = self.groups.lock().unwrap();
+        let group = groups.iter().find(|x| x.accepts(mapping, owner));
+        let Some(group) = group else {
+            return false;
+        };
+        let mut mappings = group.mappings.lock().unwrap();
+        if !mapping.is_virtual_host() || !mappings.iter().all(|x| x.mode.eq_ignore_ascii_case(&mapping.mode)) {
+            return false;
+        }
+        mappings.push(mapping.clone());
+        self.clear_inactive(&mapping.name, owner);
+        true
+    }
+
+    /// 登记新的监听, 返回监听任务使用的映射列表和停止通知
+    pub fn add_group(&self, listen: SocketAddr, mapping: &MappingConfig, owner: Option<&str>) -> (SharedMappings, watch::Receiver<String>) {
+        let (stop, stop_rx) = watch::channel::<String>(String::from("cmd"));
+        let mappings = Arc::new(Mutex::new(vec![mapping.clone()]));
+        self.groups.lock().unwrap().push(ListenerGroup {
+            listen,
+            udp: mapping.is_udp(),
+            owner: owner.map(|x| x.to_string()),
+            mappings: mappings.clone(),
+            stop,
+        });
+        self.clear_inactive(&mapping.name, owner);
+        (mappings, stop_rx)
+    }
+
+    /// 记录没有监听的映射
+    pub fn set_inactive(&self, mapping: &MappingConfig, owner: Option<&str>, state: ListenerState, reason: String) {
+        let mut inactive = self.inactive.lock().unwrap();
+        inactive.retain(|x| !(x.mapping.name == mapping.name && x.owner.as_deref() == owner));
+        inactive.push(ListenerInfo {
+            mapping: mapping.clone(),
+            owner: owner.map(|x| x.to_string()),
+            state,
+            reason: Some(reason),
+        });
+    }
+
+    fn clear_inactive(&self, name: &str, owner: Option<&str>) {
+        self.inactive.lock().unwrap().retain(|x| !(x.mapping.name == name && x.owner.as_deref() == owner));
+    }
+
+    /// 查找映射, 包括没有监听的映射
+    pub fn get(&self, name: &str, owner: Option<&str>) -> Option<MappingConfig> {
+        let groups = self.groups.lock().unwrap();
+        let found = groups.iter()
+            .filter(|x| x.owner.as_deref() == owner)
+            .find_map(|x| x.mappings.lock().unwrap().iter().find(|x| x.name == name).cloned());
+        found.or_else(|| {
+            self.inactive.lock().unwrap().iter()
+                .find(|x| x.mapping.name == name && x.owner.as_deref() == owner)
+                .map(|x| x.mapping.clone())
+        })
+    }
+
+    /// 在原监听上替换同名映射, 监听地址或协议变化等需要重新监听时返回 false
+    pub fn replace(&self, mapping: &MappingConfig, owner: Option<&str>) -> bool {
+        let groups = self.groups.lock().unwrap();
+        for group in groups.iter().filter(|x| x.owner.as_deref() == owner) {
+            let mut mappings = group.mappings.lock().unwrap();
+            let Some(index) = mappings.iter().position(|x| x.name == mapping.name) else {
+                continue;
+            };
+            let compatible = group.accepts(mapping, owner)
+                && (mappings.len() == 1 || (mapping.is_virtual_host() && mapping.mode.eq_ignore_ascii_case(&mappings[index].mode)));
+            if compatible {
+                mappings[index] = mapping.clone();
+            }
+            return compatible;
+        }
+        false
+    }
+
+    /// 移除映射, 所在监听没有其它映射时停止监听. 已建立的会话不受影响
+    pub fn remove(&self, name: &str, owner: Option<&str>) -> Option<MappingConfig> {
+        let mut removed = None;
+        self.inactive.lock().unwrap().retain(|x| {
+            if x.mapping.name == name && x.owner.as_deref() == owner {
+                removed = Some(x.mapping.clone());
+                return false;
+            }
+            true
+        });
+        let mut groups = self.groups.lock().unwrap();
+        groups.retain(|group| {
+            if group.owner.as_deref() != owner {
+                return true;
+            }
+            let mut mappings = group.mappings.lock().unwrap();
+            let Some(index) = mappings.iter().position(|x| x.name == name) else {
+                return true;
+            };
+            removed = Some(mappings.remove(index));
+            if !mappings.is_empty() {
+                return true;
+            }
+            log::info!("stop listening on {}", group.listen);
+            group.stop.send(String::from("stop")).unwrap_or(());
+            false
+        });
+        removed
+    }
+
+    /// 监听任务退出时移除对应的分组
+    pub fn remove_group(&self, mappings: &SharedMappings) {
+        self.groups.lock().unwrap().retain(|x| !Arc::ptr_eq(&x.mappings, mappings));
+    }
+
+    /// 停止客户端声明的所有映射的监听
+    pub fn remove_owner(&self, owner: &str) {
+        self.inactive.lock().unwrap().retain(|x| x.owner.as_deref() != Some(owner));
+        self.groups.lock().unwrap().retain(|group| {
+            if group.owner.as_deref() != Some(owner) {
+                return true;
+            }
+            group.stop.send(String::from("stop")).unwrap_or(());
+            false
+        });
+    }
+
+    pub fn list(&self) -> Vec<ListenerInfo> {
+        let groups = self.groups.lock().unwrap();
+        let mut list: Vec<ListenerInfo> = groups.iter().flat_map(|group| {
+            group.mappings.lock().unwrap().iter().map(|mapping| ListenerInfo {
+                mapping: mapping.clone(),
+                owner: group.owner.clone(),
+                state: ListenerState::Listening,
+                reason: None,
+            }).collect::<Vec<_>>()
+        }).collect();
+        list.extend(self.inactive.lock().unwrap().iter().cloned());
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(name: &str, mode: &str, listen: &str, domain: &str) -> MappingConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "mode": mode,
+            "listen": listen,
+            "forward": "127.0.0.1:80",
+            "domain": domain,
+        })).unwrap()
+    }
+
+    /// 收到停止通知或发送端已释放, 监听任务都会退出
+    fn stopped(stop_rx: &watch::Receiver<String>) -> bool {
+        stop_rx.has_changed().unwrap_or(true)
+    }
+
+    fn names(listeners: &ProxyListeners) -> Vec<String> {
+        let mut names: Vec<String> = listeners.list().into_iter().map(|x| x.mapping.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn virtual_hosts_share_listener() {
+        let listeners = ProxyListeners::new();
+        let web1 = mapping("web1", "httpreverse", "0.0.0.0:8080", "a.com");
+        assert!(!listeners.join_group(&web1, None));
+        let (mappings, _stop_rx) = listeners.add_group(web1.listen.unwrap(), &web1, None);
+
+        assert!(listeners.join_group(&mapping("web2", "httpreverse", "0.0.0.0:8080", "b.com"), None));
+        assert_eq!(mappings.lock().unwrap().len(), 2);
+        // 不同模式, 非虚拟主机或其它客户端声明的映射不能共享监听
+        assert!(!listeners.join_group(&mapping("tls", "https", "0.0.0.0:8080", "c.com"), None));
+        assert!(!listeners.join_group(&mapping("tcp", "tcp", "0.0.0.0:8080", ""), None));
+        assert!(!listeners.join_group(&mapping("web3", "httpreverse", "0.0.0.0:8080", "c.com"), Some("client1")));
+        assert!(!listeners.join_group(&mapping("web4", "httpreverse", "0.0.0.0:8081", "d.com"), None));
+        assert_eq!(names(&listeners), vec!["web1", "web2"]);
+    }
+
+    #[test]
+    fn remove_stops_listener_with_last_mapping() {
+        let listeners = ProxyListeners::new();
+        let web1 = mapping("web1", "httpreverse", "0.0.0.0:8080", "a.com");
+        let (_mappings, stop_rx) = listeners.add_group(web1.listen.unwrap(), &web1, None);
+        assert!(listeners.join_group(&mapping("web2", "httpreverse", "0.0.0.0:8080", "b.com"), None));
+
+        assert_eq!(listeners.remove("web1", None).map(|x| x.name), Some(String::from("web1")));
+        assert!(!stopped(&stop_rx));
+        assert!(listeners.remove("web2", Some("client1")).is_none());
+        assert!(listeners.remove("web2", None).is_some());
+        assert!(stopped(&stop_rx));
+        assert!(listeners.list().is_empty());
+        assert!(listeners.remove("web2", None).is_none());
+    }
+
+    #[test]
+    fn replace_in_place_or_relisten() {
+        let listeners = ProxyListeners::new();
+        let tcp = mapping("tcp", "tcp", "0.0.0.0:9000", "");
+        let (mappings, _stop_rx) = listeners.add_group(tcp.listen.unwrap(), &tcp, None);
+
+        let mut changed = tcp.clone();
+        changed.forward = String::from("127.0.0.1:81");
+        assert!(listeners.replace(&changed, None));
+        assert_eq!(mappings.lock().unwrap()[0].forward, "127.0.0.1:81");
+
+        // 监听地址或协议变化需要重新监听
+        assert!(!listeners.replace(&mapping("tcp", "tcp", "0.0.0.0:9001", ""), None));
+        assert!(!listeners.replace(&mapping("tcp", "udp", "0.0.0.0:9000", ""), None));
+        assert!(!listeners.replace(&mapping("other", "tcp", "0.0.0.0:9000", ""), None));
+    }
+
+    #[test]
+    fn inactive_mappings_are_listed_and_removed() {
+        let listeners = ProxyListeners::new();
+        let socks = mapping("socks", "socks5", "0.0.0.0:1080", "");
+        listeners.set_inactive(&socks, None, ListenerState::Skipped, String::from("mode socks5 is not enabled"));
+        assert_eq!(listeners.get("socks", None), Some(socks.clone()));
+        assert_eq!(listeners.list()[0].state, ListenerState::Skipped);
+
+        // 开始监听后不再是未监听状态
+        listeners.add_group(socks.listen.unwrap(), &socks, None);
+        let list = listeners.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].state, ListenerState::Listening);
+    }
+
+    #[test]
+    fn remove_owner_stops_client_listeners() {
+        let listeners = ProxyListeners::new();
+        let declared = mapping("declared", "tcp", "0.0.0.0:9000", "");
+        let (_mappings, stop_rx) = listeners.add_group(declared.listen.unwrap(), &declared, Some("client1"));
+        let config = mapping("config", "tcp", "0.0.0.0:9001", "");
+        listeners.add_group(config.listen.unwrap(), &config, None);
+
+        assert!(listeners.get("declared", None).is_none());
+        listeners.remove_owner("client1");
+        assert!(stopped(&stop_rx));
+        assert_eq!(names(&listeners), vec!["config"]);
+    }
+}
@@ -1,3 +1,11 @@
 mod node_server;
+mod registry;
+mod pending;
+mod listeners;
+mod admin;
 
-pub use node_server::*;
\ No newline at end of file
+pub use node_server::*;
+pub use registry::*;
+pub use pending::*;
+pub use listeners::*;
+use admin::*;
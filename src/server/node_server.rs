@@ -1,329 +1,1320 @@
 use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::utils::{new_tls_acceptor};
-use crate::{proto, mappings};
-use serde_json;
-use tokio::sync::{mpsc,oneshot,watch};
+use crate::utils::{new_tls_acceptor, tcp_connect, tcp_listen, udp_bind};
+use crate::proto;
+use crate::proxy;
+use crate::mux::{new_mux_session, MuxMode};
+use crate::admin::{start_admin, Metrics, SessionTable};
+use super::{ClientHandle, ClientRegistry, ListenerState, PendingRequests, ProxyListeners, ServerAdmin, SharedMappings};
+use tokio::sync::{mpsc,oneshot,watch,Notify};
 
 use tokio::select;
+use tokio::io::AsyncWriteExt;
 use tokio::time:: {
-    sleep, Duration
+    sleep, timeout, Duration
 };
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::sync::{Arc, Mutex, RwLock};
+use ring::{hmac, rand::{SecureRandom, SystemRandom}};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio_rustls::{
+    TlsAcceptor,
     server::TlsStream as TlsServerStream,
 };
 use crate::{
-    get_datetime14,
     generate_uuid,
-    tls_server_read_to,
+    get_datetime,
+    parse_cert_identity,
+    stream_forward,
+    AsyncStream,
+    BoxStream,
+    CertIdentity,
+    CountedStream,
 };
 use crate::{
-    error::AppTypeResult, AppOption, AppResult,MappingConfig,AppError
+    AppOption, AppResult, ClientPolicy, MappingConfig
 };
 
-const META_MSG_END_FLAG: [u8;1] = [0;1];
 const FORWARD_CONNECTION_BIND_TIMEOUT: u64 = 5;
-const MAIN_CONNECTION_KEEPALIVE_TIMEOUT: u64 = 120;
+const CONNECTION_HANDSHAKE_TIMEOUT: u64 = 10;
+const CLIENT_CMD_QUEUE_SIZE: usize = 1000;
+const PASSIVE_CLIENT_CHECK_INTERVAL: u64 = 3;
+const PASSIVE_CLIENT_RECONNECT_TIMEOUT: u64 = 5;
+const UDP_SESSION_QUEUE_SIZE: usize = 256;
+const MAPPING_BIND_RETRIES: usize = 3;
+const MAPPING_BIND_RETRY_DELAY: u64 = 500;
+/// 移除或修改映射后, 已建立的会话最多继续转发的秒数
+const MAPPING_DRAIN_TIMEOUT: u64 = 60;
+/// 接受连接出错(如文件句柄耗尽)后等待的毫秒数, 监听不会因此停止
+const ACCEPT_ERROR_DELAY: u64 = 100;
 
-pub async fn start_server_node(option: AppOption, main_cli_rx: watch::Receiver<String>) -> AppResult<()> {
-    let datetime =  get_datetime14();
+type ForwardConn = (String, String, BoxStream, SocketAddr);
+type HandshakeConn = (String, TlsServerStream<TcpStream>, SocketAddr, CertIdentity);
+/// 被动模式握手结果: 连接, 证书身份, 地址, 客户端名称, 连接ID, 协商的协议版本
+type DialHandshake = (TlsServerStream<TcpStream>, CertIdentity, SocketAddr, String, String, Option<u32>);
+/// 等待数据连接的转发请求: bind_id -> (目标客户端, 通知通道)
+type BindQueue = Arc<Mutex<HashMap<String, (String, oneshot::Sender<ForwardConn>)>>>;
+
+/// 代理任务共享的服务端状态
+#[derive(Clone)]
+pub(super) struct ServerContext {
+    pub(super) registry: ClientRegistry,
+    bind_queue: BindQueue,
+    bind_key: Arc<hmac::Key>,
+    data_enabled: bool,
+    /// 重新加载配置时替换, 新的连接使用新的证书
+    tls_acceptor: Arc<RwLock<TlsAcceptor>>,
+    proxy_pass: Option<String>,
+    /// 心跳间隔和超时秒数
+    heartbeat: (u64, u64),
+    proxy_on: Arc<Vec<String>>,
+    client_policies: Arc<Vec<ClientPolicy>>,
+    pub(super) listeners: ProxyListeners,
+    pub(super) sessions: SessionTable,
+    pub(super) metrics: Metrics,
+    /// 服务端退出通知, 配置文件中的映射随之停止监听
+    quit_rx: watch::Receiver<String>,
+}
+
+impl ServerContext {
+    fn tls_acceptor(&self) -> TlsAcceptor {
+        self.tls_acceptor.read().unwrap().clone()
+    }
+
+    /// 检查映射能否在服务端监听, 返回跳过的原因
+    fn check_mapping(&self, mapping: &MappingConfig) -> Result<SocketAddr, String> {
+        if !self.proxy_on.iter().any(|x| x.eq_ignore_ascii_case(&mapping.mode)) {
+            return Err(format!("mode {} is not enabled in proxy_on", mapping.mode));
+        }
+        if !mapping.is_tcp() && !mapping.is_socks5() && !mapping.is_http() && !mapping.is_httpreverse() && !mapping.is_https() && !mapping.is_udp() {
+            return Err(format!("mode {} is not supported", mapping.mode));
+        }
+        mapping.listen.ok_or_else(|| String::from("has no listen address"))
+    }
+
+    /// 运行时新增配置映射并开始监听
+    pub(super) async fn add_mapping(&self, mapping: &MappingConfig) -> Result<(), String> {
+        mapping.validate()?;
+        if self.listeners.get(&mapping.name, None).is_some() {
+            return Err(format!("mapping {} already exists", mapping.name));
+        }
+        self.check_mapping(mapping).map_err(|e| format!("mapping {} {}", mapping.name, e))?;
+        server_listen_mapping(self, mapping, None, self.quit_rx.clone()).await
+            .map_err(|e| format!("mapping {} listen error: {}", mapping.name, e))?;
+        log::info!("mapping {} added", mapping.name);
+        Ok(())
+    }
+
+    /// 修改配置映射, 新连接使用新的配置, 原有会话按移除映射的方式结束
+    pub(super) async fn update_mapping(&self, mapping: &MappingConfig) -> Result<(), String> {
+        mapping.validate()?;
+        self.check_mapping(mapping).map_err(|e| format!("mapping {} {}", mapping.name, e))?;
+        let bind_ids = self.sessions.mapping_sessions(&mapping.name);
+        // 监听地址和协议不变时直接替换, 否则重新监听
+        if !self.listeners.replace(mapping, None) {
+            let old = self.listeners.remove(&mapping.name, None);
+            if let Err(e) = server_listen_mapping(self, mapping, None, self.quit_rx.clone()).await {
+                if let Some(old) = old {
+                    log::warn!("mapping {} update failed, restore previous config", mapping.name);
+                    server_start_proxy(&[old], None, self.clone(), self.quit_rx.clone()).await.unwrap_or(());
+                }
+                return Err(format!("mapping {} listen error: {}", mapping.name, e));
+            }
+        }
+        log::info!("mapping {} updated", mapping.name);
+        self.drain_sessions(&mapping.name, bind_ids);
+        Ok(())
+    }
+
+    /// 移除配置映射, 不再接受新连接, 已建立的会话继续转发直到结束或超过 `MAPPING_DRAIN_TIMEOUT`
+    pub(super) fn remove_mapping(&self, name: &str) -> Option<MappingConfig> {
+        let mapping = self.listeners.remove(name, None)?;
+        log::info!("mapping {} removed", name);
+        self.drain_sessions(name, self.sessions.mapping_sessions(name));
+        Some(mapping)
+    }
+
+    fn drain_sessions(&self, name: &str, bind_ids: Vec<String>) {
+        if bind_ids.is_empty() {
+            return;
+        }
+        log::info!("mapping {} draining {} sessions", name, bind_ids.len());
+        let sessions = self.sessions.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(MAPPING_DRAIN_TIMEOUT)).await;
+            let closed = bind_ids.iter().filter(|x| sessions.close(x)).count();
+            if closed > 0 {
+                log::info!("mapping {} drain timeout, close {} sessions", name, closed);
+            }
+        });
+    }
+}
+
+/// 数据连接凭证: HMAC(bind_id:client), 只有收到转发请求的客户端才能得到
+fn bind_token(key: &hmac::Key, bind_id: &str, client: &str) -> String {
+    let tag = hmac::sign(key, format!("{}:{}", bind_id, client).as_bytes());
+    tag.as_ref().iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn verify_bind_token(key: &hmac::Key, bind_id: &str, client: &str, token: &str) -> bool {
+    let Some(tag) = decode_hex(token) else {
+        return false;
+    };
+    hmac::verify(key, format!("{}:{}", bind_id, client).as_bytes(), &tag).is_ok()
+}
+
+// `usize::is_multiple_of` 需要 Rust 1.87, 这里保持对旧版本编译器的兼容
+#[allow(clippy::manual_is_multiple_of)]
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| value.get(i..i + 2).and_then(|x| u8::from_str_radix(x, 16).ok()))
+        .collect()
+}
+
+fn new_bind_key() -> hmac::Key {
+    let mut secret = [0u8; 32];
+    SystemRandom::new().fill(&mut secret).expect("cannot generate bind key");
+    hmac::Key::new(hmac::HMAC_SHA256, &secret)
+}
+
+pub async fn start_server_node(option: AppOption, main_cli_rx: watch::Receiver<String>, reload_rx: watch::Receiver<AppOption>) -> AppResult<()> {
     log::info!("proxy server running ...");
     let ca_file = option.ca_cert.clone().unwrap();
     let cert_file = option.cert.clone().unwrap();
     let key_file = option.key.clone().unwrap();
 
-    let server_signal_addr = SocketAddr::new(option.listen.clone(), option.signal_port);
-    let data_signal_addr = SocketAddr::new(option.listen.clone(), option.data_port);
-    let tls_acceptor = new_tls_acceptor(&ca_file, &cert_file, &key_file);
-
-    let main_listener = TcpListener::bind(server_signal_addr).await.unwrap();
-    let data_listener = TcpListener::bind(data_signal_addr).await.unwrap();
-    let mut bind_queue = HashMap::new();
-    
-    //let (cmd_tx, mut cmd_rx) = mpsc::channel::<(String, SocketAddr)>(32);
-
-    let (clear_tx, mut clear_rx) = mpsc::channel::<String>(1000);
-    let (fwd_tx, mut fwd_rx) = mpsc::channel::<(String, String, TlsServerStream<TcpStream>, SocketAddr)>(1000);
-    let (proxy_tx, mut proxy_rx) = mpsc::channel::<(String, String,  oneshot::Sender<(String, String, TlsServerStream<TcpStream>, SocketAddr)>)>(1000);
-    let (_socket, _peer_addr)  = main_listener.accept().await.unwrap();
-    let mut main_tls_stream = tls_acceptor.accept(_socket).await.unwrap();
-    let mut recv_buffer: Vec<u8> = Vec::new();
-    let size = tls_server_read_to(&mut main_tls_stream, &mut recv_buffer, 0).await.unwrap();
-    let res = String::from_utf8(recv_buffer).unwrap();
-    log::info!("Received client connection: {}", res);
-    let bind_v:Vec<&str> = res.split(":").collect();
-    let stream_type= bind_v[0].to_string();
-    if stream_type != "main" {
-        log::error!("recv stream type: {}, error", stream_type);
-        return Ok(());
+    let server_signal_addr = SocketAddr::new(option.listen, option.signal_port);
+    let tls_acceptor = new_tls_acceptor(&ca_file, &cert_file, &key_file)?;
+
+    // 被动模式下由服务端连接客户端, 不需要监听信令和数据端口
+    let (main_listener, data_listener) = if option.is_passive() {
+        (None, None)
+    } else {
+        let main_listener = tcp_listen(server_signal_addr)?;
+        let data_listener = match option.data_port {
+            Some(port) => Some(tcp_listen(SocketAddr::new(option.listen, port))?),
+            None => None,
+        };
+        (Some(main_listener), data_listener)
+    };
+
+    let ctx = ServerContext {
+        registry: ClientRegistry::new(),
+        bind_queue: Arc::new(Mutex::new(HashMap::new())),
+        bind_key: Arc::new(new_bind_key()),
+        data_enabled: data_listener.is_some(),
+        tls_acceptor: Arc::new(RwLock::new(tls_acceptor)),
+        proxy_pass: option.proxy_pass.clone(),
+        heartbeat: (option.heartbeat_interval, option.heartbeat_timeout),
+        proxy_on: Arc::new(option.proxy_on.clone()),
+        client_policies: Arc::new(option.client_policies.clone()),
+        listeners: ProxyListeners::new(),
+        sessions: SessionTable::new(),
+        metrics: Metrics::new(),
+        quit_rx: main_cli_rx.clone(),
+    };
+
+    if let Some(admin_listen) = option.admin_listen {
+        let admin = ServerAdmin {
+            ctx: ctx.clone(),
+        };
+        start_admin(admin_listen, option.admin_token.clone(), Arc::new(admin), main_cli_rx.clone())?;
     }
 
-    let proxy_task = server_start_proxy(&option.mappings, proxy_tx, main_cli_rx).await.unwrap();
+    server_start_proxy(&option.mappings, None, ctx.clone(), main_cli_rx).await?;
     log::debug!("start proxy ....");
-    
+    tokio::spawn(server_reload(ctx.clone(), option.clone(), reload_rx));
+
+    if option.is_passive() {
+        if option.client_addrs.is_empty() {
+            log::warn!("passive mode without client_addrs, no client will be connected");
+        }
+        let mux_channels = if option.mux { option.mux_channels.max(1) } else { 0 };
+        for addr in &option.client_addrs {
+            tokio::spawn(server_passive_client(ctx.clone(), addr.clone(), mux_channels));
+        }
+    }
+
+    let (conn_tx, mut conn_rx) = mpsc::channel::<HandshakeConn>(100);
     loop {
-        let mut recv_buffer: Vec<u8> = Vec::new();
         select! {
-            tls_msg = tls_server_read_to(&mut main_tls_stream, &mut recv_buffer, 0) => {
+            main_accept = accept_opt(&main_listener) => {
+                let (_socket, _peer_addr) = match main_accept {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        log::error!("Failed to accept signal connection: {}", e);
+                        sleep(Duration::from_millis(ACCEPT_ERROR_DELAY)).await;
+                        continue;
+                    }
+                };
+                spawn_handshake(ctx.clone(), _socket, _peer_addr, conn_tx.clone());
+            },
+
+            data_accept = accept_opt(&data_listener) => {
+                let (_socket, _peer_addr) = match data_accept {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        log::error!("Failed to accept data connection: {}", e);
+                        sleep(Duration::from_millis(ACCEPT_ERROR_DELAY)).await;
+                        continue;
+                    }
+                };
+                spawn_handshake(ctx.clone(), _socket, _peer_addr, conn_tx.clone());
+            },
+
+            conn_msg = conn_rx.recv() => {
+                if let Some((res, tls_stream, _peer_addr, identity)) = conn_msg {
+                    log::debug!("Received from connection {}: {}", _peer_addr, res);
+                    let bind_v:Vec<&str> = res.split(':').collect();
+                    if bind_v.len() < 3 || (bind_v[0] == "data" && bind_v.len() != 4) {
+                        log::error!("Received msg type error: {}", res);
+                        continue;
+                    }
+
+                    let Some(client_name) = verify_client_name(bind_v[1], &identity) else {
+                        log::error!("client name {} does not match certificate {} from {}", bind_v[1], identity.subject, _peer_addr);
+                        continue;
+                    };
+
+                    match bind_v[0] {
+                        "main" => {
+                            let Ok(version) = bind_v.get(3).map(|x| proto::negotiate_version(x).ok_or(x)).transpose() else {
+                                log::error!("unsupported protocol version from {}: {}", _peer_addr, res);
+                                continue;
+                            };
+                            server_register_client(&ctx, client_name, bind_v[2], _peer_addr, identity, tls_stream, None, version);
+                        },
+                        "mux" => {
+                            let (mux_control, _) = new_mux_session(tls_stream, MuxMode::Server);
+                            if ctx.registry.add_mux(&client_name, bind_v[2], mux_control.clone()) {
+                                log::info!("mux channel connected: {} from {}", client_name, _peer_addr);
+                            } else {
+                                log::error!("mux channel from unknown client: {}", res);
+                                mux_control.close();
+                            }
+                        },
+                        "data" => {
+                            let bind_id= bind_v[2].to_string();
+                            log::debug!("bind request client:{} id:{} ", client_name, bind_id);
+                            if !verify_bind_token(&ctx.bind_key, &bind_id, &client_name, bind_v[3]) {
+                                log::error!("invalid bind token from client:{} id:{} addr:{}", client_name, bind_id, _peer_addr);
+                                continue;
+                            }
+
+                            let mut bind_queue = ctx.bind_queue.lock().unwrap();
+                            match bind_queue.get(&bind_id) {
+                                Some((target, _)) if *target != client_name => {
+                                    log::error!("bind id:{} requested for client {} but connected by {}", bind_id, target, client_name);
+                                },
+                                Some(_) => {
+                                    let (_, tx) = bind_queue.remove(&bind_id).unwrap();
+                                    if !tx.is_closed() {
+                                        tx.send((bind_id, client_name, Box::new(tls_stream), _peer_addr)).unwrap_or(());
+                                    } else {
+                                        log::debug!("proxy tx is closed, ignore: {}", bind_id);
+                                    }
+                                },
+                                None => {
+                                    log::error!("Cannot find match binding for: {}", bind_id);
+                                }
+                            }
+                        },
+                        _ => {
+                            log::error!("Received msg type error: {}", res);
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// 应用重新加载的配置: 替换证书, 按名称对比配置文件中的映射并新增, 修改或移除.
+/// 通过管理接口修改的映射只有在配置文件中同名映射变化时才会被覆盖
+async fn server_reload(ctx: ServerContext, mut option: AppOption, mut reload_rx: watch::Receiver<AppOption>) {
+    let mut quit_rx = ctx.quit_rx.clone();
+    reload_rx.borrow_and_update();
+    loop {
+        select! {
+            changed = reload_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            },
+            _ = quit_rx.changed() => {
+                log::debug!("reload task recv app quit msg");
+                break;
+            }
+        }
+        let new = reload_rx.borrow_and_update().clone();
+
+        if let (Some(ca_file), Some(cert_file), Some(key_file)) = (&new.ca_cert, &new.cert, &new.key) {
+            match new_tls_acceptor(ca_file, cert_file, key_file) {
+                Ok(tls_acceptor) => {
+                    *ctx.tls_acceptor.write().unwrap() = tls_acceptor;
+                    log::info!("server certificate reloaded");
+                },
+                Err(e) => log::error!("reload server certificate failed: {}", e),
+            }
+        }
+
+        for old in &option.mappings {
+            if !new.mappings.iter().any(|x| x.name == old.name) {
+                ctx.remove_mapping(&old.name);
+            }
+        }
+        for mapping in &new.mappings {
+            let result = match option.mappings.iter().find(|x| x.name == mapping.name) {
+                Some(old) if old == mapping => continue,
+                Some(_) => ctx.update_mapping(mapping).await,
+                None if ctx.listeners.get(&mapping.name, None).is_some() => Err(format!("mapping {} already exists", mapping.name)),
+                None => server_start_proxy(std::slice::from_ref(mapping), None, ctx.clone(), ctx.quit_rx.clone()).await.map_err(|e| e.to_string()),
+            };
+            if let Err(e) = result {
+                log::error!("reload mapping {} failed: {}", mapping.name, e);
+            }
+        }
+        option = new;
+    }
+}
+
+/// 注册客户端的信令连接并启动处理任务. `version` 为客户端握手中声明版本后协商的结果, 旧客户端不声明版本
+#[allow(clippy::too_many_arguments)]
+fn server_register_client(ctx: &ServerContext, name: String, client_id: &str, peer_addr: SocketAddr, identity: CertIdentity
+    , tls_stream: TlsServerStream<TcpStream>, dial_addr: Option<String>, version: Option<u32>) {
+    let (cmd_tx, cmd_rx) = mpsc::channel::<proto::ProtoCmd>(CLIENT_CMD_QUEUE_SIZE);
+    let handle = ClientHandle {
+        name,
+        client_id: client_id.to_string(),
+        peer_addr,
+        identity,
+        cmd_tx,
+        shutdown: Arc::new(Notify::new()),
+        dial_addr,
+        pending: PendingRequests::new(),
+        version: version.unwrap_or(proto::CMD_VERSION_LEGACY),
+        connected_at: get_datetime(),
+    };
+    log::info!("Received client connection: {} from {} cert: {} version: {}", handle.name, peer_addr, handle.identity.subject, handle.version);
+    ctx.registry.register(handle.clone());
+    let heartbeat = proto::Heartbeat::new(ctx.heartbeat.0, ctx.heartbeat.1);
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        let mut tls_stream = tls_stream;
+        if version.is_some() {
+            if let Err(e) = proto::write_version(&mut tls_stream, handle.version).await {
+                log::error!("Failed to reply protocol version to client {}: {}", handle.name, e);
+                ctx.registry.unregister(&handle.name, &handle.client_id);
+                return;
+            }
+        }
+        server_client_node(ctx, handle, tls_stream, cmd_rx, heartbeat).await
+    });
+}
+
+/// 处理单个客户端的信令连接, 直到连接断开或被同名客户端替换
+async fn server_client_node(ctx: ServerContext, handle: ClientHandle, mut tls_stream: TlsServerStream<TcpStream>
+    , mut cmd_rx: mpsc::Receiver<proto::ProtoCmd>, mut heartbeat: proto::Heartbeat) {
+    let mut recv_buffer: Vec<u8> = Vec::new();
+    // 客户端声明的映射, 释放后对应的监听随之关闭
+    let mut client_proxy: Option<watch::Sender<String>> = None;
+    loop {
+        select! {
+            tls_msg = proto::read_cmd(&mut tls_stream, &mut recv_buffer) => {
                 match tls_msg {
-                    Ok(size)=> {
-                        if size > 0 {
-                            let res = String::from_utf8(recv_buffer).unwrap();
-                            log::debug!("recv from client: {}", res);
-                            let recv_cmd: proto::ProtoCmd = serde_json::from_str(&res).unwrap();
-                        } else {
-                            log::debug!("signal connection read {}", size);
-                            //return Ok(());
+                    Ok(recv_cmd)=> {
+                        log::debug!("recv from client {}: {:?}", handle.name, recv_cmd);
+                        heartbeat.received();
+                        match recv_cmd {
+                            proto::ProtoCmd::Request(req) => {
+                                let rspcmd = match &req.body {
+                                    proto::ProtoCmdBody::Ping => proto::pong(&req),
+                                    proto::ProtoCmdBody::Conf(proto::ClientConf { mappings }) => {
+                                        // 重新声明时先关闭之前的监听
+                                        client_proxy = None;
+                                        ctx.listeners.remove_owner(&handle.name);
+                                        let (status, message) = match server_start_client_proxy(&ctx, &handle.name, mappings).await {
+                                            Ok(proxy_tx) => {
+                                                client_proxy = Some(proxy_tx);
+                                                log::info!("client {} registered {} mappings", handle.name, mappings.len());
+                                                (proto::STATUS_OK, format!("{} mappings registered", mappings.len()))
+                                            },
+                                            Err(e) => {
+                                                log::error!("client {} mappings rejected: {}", handle.name, e);
+                                                (proto::STATUS_ERROR, e)
+                                            }
+                                        };
+                                        proto::ProtoCmd::Response(req.response(status, message))
+                                    },
+                                    _ => {
+                                        log::warn!("unsupported request from client {}: {:?}", handle.name, req.kind());
+                                        req.unsupported()
+                                    }
+                                };
+                                if let Err(e) = proto::write_cmd(&mut tls_stream, &rspcmd).await {
+                                    log::error!("Failed to send message to client {}: {}", handle.name, e);
+                                    break;
+                                }
+                            },
+                            proto::ProtoCmd::Response(rsp) => {
+                                handle.pending.complete(rsp);
+                            }
                         }
                     },
                     Err(e) => {
-                        let err_kind = e.kind();
-                        match err_kind {
+                        match e.kind() {
                             std::io::ErrorKind::UnexpectedEof => {
-                                log::info!("client connection closed");
-                                return Ok(());
+                                log::info!("client {} connection closed", handle.name);
                             },
                             _ => {
-                                log::error!("Failed to receive message from client: {}", e);
-                                return Err(e.into());
+                                log::error!("Failed to receive message from client {}: {}", handle.name, e);
                             }
                         }
-                        
+                        break;
                     }
                 }
-                
             },
-           
-            data_accept = data_listener.accept() => {
-                let (_socket, _peer_addr) = data_accept.unwrap(); 
-                let tls_accept_result = tls_acceptor.accept(_socket).await;
-                match tls_accept_result {
-                    Ok(mut tls_stream) => {
-                        log::debug!("forward: Accepted fwd conn with TLS");
-
-                        let mut recv_buffer: Vec<u8> = Vec::new();
-                        let size = tls_server_read_to(&mut tls_stream, &mut recv_buffer, 0).await.unwrap();
-                        let res = String::from_utf8(recv_buffer).unwrap();
-                        log::debug!("Received from forward connection: {}", res);
-                        let bind_v:Vec<&str> = res.split(":").collect();
-                        let stream_type= bind_v[0].to_string();
-                        if stream_type != "data" {
-                            log::error!("Received msg type error: {}", res);
-                            continue;
-                        }
+            cmd_msg = cmd_rx.recv() => {
+                let Some(reqcmd) = cmd_msg else {
+                    break;
+                };
+                if let Err(e) = proto::write_cmd(&mut tls_stream, &reqcmd).await {
+                    log::error!("Failed to send message to client {}: {}", handle.name, e);
+                    break;
+                }
+            },
+            _ = handle.shutdown.notified() => {
+                log::info!("client {} connection {} shutdown", handle.name, handle.client_id);
+                break;
+            },
+            ping = heartbeat.tick() => {
+                let result = match ping {
+                    Ok(reqcmd) => proto::write_cmd(&mut tls_stream, &reqcmd).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    log::error!("Heartbeat to client {} failed: {}", handle.name, e);
+                    break;
+                }
+            }
+        }
+    }
 
-                        let client_id= bind_v[1].to_string();
-                        let bind_id= bind_v[2].to_string();
-                        log::debug!("forward: {}", res);
+    handle.pending.clear();
+    if client_proxy.take().is_some() {
+        log::info!("close mappings of client {}", handle.name);
+    }
+    // 已被同名的新连接替换时, 由新连接声明的映射接管监听
+    if ctx.registry.get(&handle.name).is_none_or(|x| x.client_id == handle.client_id) {
+        ctx.listeners.remove_owner(&handle.name);
+    }
+    ctx.registry.unregister(&handle.name, &handle.client_id);
+}
 
-                        fwd_tx.send((bind_id, client_id, tls_stream, _peer_addr)).await.unwrap();
-                    },
-                    Err(e) => {
-                        log::error!("Failed to accept TLS connection: {}", e);
-                    }
-                }
+/// 按策略检查客户端声明的映射并开始监听, 返回的发送端释放后监听关闭
+async fn server_start_client_proxy(ctx: &ServerContext, client: &str, mappings: &[MappingConfig]) -> Result<watch::Sender<String>, String> {
+    let mut client_mappings = vec![];
+    for mapping in mappings {
+        mapping.validate()?;
+        if !ctx.proxy_on.iter().any(|x| x.eq_ignore_ascii_case(&mapping.mode)) {
+            return Err(format!("mapping {} mode {} is not enabled", mapping.name, mapping.mode));
+        }
+        if !ctx.client_policies.iter().any(|x| x.allows(client, mapping)) {
+            let listen = mapping.listen.map(|x| x.to_string()).unwrap_or_default();
+            return Err(format!("mapping {} listen {} is not allowed for client {}", mapping.name, listen, client));
+        }
+        let mut mapping = mapping.clone();
+        mapping.client = client.to_string();
+        client_mappings.push(mapping);
+    }
 
-                
-            },
+    let (proxy_tx, proxy_rx) = watch::channel::<String>(String::from("cmd"));
+    match server_start_proxy(&client_mappings, Some(client), ctx.clone(), proxy_rx).await {
+        Ok(_) => Ok(proxy_tx),
+        Err(e) => Err(format!("listen error: {}", e)),
+    }
+}
+
+async fn accept_opt(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
 
-            fwd_msg = fwd_rx.recv() => {
-                if let Some(msg) = fwd_msg {
-                    let (bind_id, client_id, tls_stream, _peer_addr) = msg;
-                    log::debug!("bind request client:{} id:{} ", client_id, bind_id);
-                    if bind_queue.contains_key(&bind_id) {
-                        let tx: oneshot::Sender<(String, String, TlsServerStream<TcpStream>, SocketAddr)>  = bind_queue.remove(&bind_id).unwrap();
-                        if(!tx.is_closed()) {
-                            tx.send((bind_id, client_id,  tls_stream, _peer_addr)).unwrap();
-                        } else {
-                            log::debug!("proxy tx is closed, ignore: {}", bind_id);
+/// 完成 TLS 握手并取得客户端证书身份, 失败时计入握手失败次数
+async fn server_tls_accept(ctx: &ServerContext, socket: TcpStream) -> std::io::Result<(TlsServerStream<TcpStream>, CertIdentity)> {
+    let accept = async {
+        let tls_stream = ctx.tls_acceptor().accept(socket).await?;
+        let identity = tls_stream.get_ref().1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(parse_cert_identity)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::PermissionDenied, "no valid client certificate"))?;
+        Ok((tls_stream, identity))
+    };
+    let result = accept.await;
+    if result.is_err() {
+        ctx.metrics.tls_handshake_failed();
+    }
+    result
+}
+
+async fn server_handshake(ctx: &ServerContext, socket: TcpStream) -> std::io::Result<(String, TlsServerStream<TcpStream>, CertIdentity)> {
+    let (mut tls_stream, identity) = server_tls_accept(ctx, socket).await?;
+    let res = proto::read_meta(&mut tls_stream).await?;
+    Ok((res, tls_stream, identity))
+}
+
+/// 被动模式下连接客户端, TCP 由服务端发起, TLS 角色不变, 随后告知客户端连接用途
+async fn server_dial(ctx: &ServerContext, addr: &str, purpose: &str) -> std::io::Result<(TlsServerStream<TcpStream>, CertIdentity, SocketAddr)> {
+    let dial = async {
+        let socket = tcp_connect(addr, false).await?;
+        let peer_addr = socket.peer_addr()?;
+        let (mut tls_stream, identity) = server_tls_accept(ctx, socket).await?;
+        proto::write_meta(&mut tls_stream, purpose).await?;
+        Ok((tls_stream, identity, peer_addr))
+    };
+
+    timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), dial)
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "dial client timeout")))
+}
+
+/// 连接客户端并读取它的应答 `type:name:id[:version]`, 返回经过证书校验的名称, 连接ID和协商的版本
+async fn server_dial_handshake(ctx: &ServerContext, addr: &str, purpose: &str) -> std::io::Result<DialHandshake> {
+    let (mut tls_stream, identity, peer_addr) = server_dial(ctx, addr, purpose).await?;
+    let res = timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), proto::read_meta(&mut tls_stream))
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "client handshake timeout")))?;
+
+    let bind_v: Vec<&str> = res.split(':').collect();
+    if !(bind_v.len() == 3 || bind_v.len() == 4) || bind_v[0] != purpose {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unexpected client reply: {}", res)));
+    }
+    let Some(client_name) = verify_client_name(bind_v[1], &identity) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied,
+            format!("client name {} does not match certificate {}", bind_v[1], identity.subject)));
+    };
+    let client_id = bind_v[2].to_string();
+    let Ok(version) = bind_v.get(3).map(|x| proto::negotiate_version(x).ok_or(x)).transpose() else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unsupported protocol version: {}", res)));
+    };
+    Ok((tls_stream, identity, peer_addr, client_name, client_id, version))
+}
+
+/// 被动模式: 维持到一个客户端的信令连接和多路复用连接, 断开后重新连接
+async fn server_passive_client(ctx: ServerContext, addr: String, mux_channels: usize) {
+    loop {
+        match server_dial_handshake(&ctx, &addr, "main").await {
+            Ok((tls_stream, identity, peer_addr, client_name, client_id, version)) => {
+                server_register_client(&ctx, client_name.clone(), &client_id, peer_addr, identity, tls_stream, Some(addr.clone()), version);
+
+                while let Some(count) = ctx.registry.mux_count(&client_name, &client_id) {
+                    for _ in count..mux_channels {
+                        match server_dial_handshake(&ctx, &addr, "mux").await {
+                            Ok((tls_stream, _, _, name, _, _)) if name != client_name => {
+                                log::error!("mux channel from {} replied by another client: {}", addr, name);
+                                drop(tls_stream);
+                            },
+                            Ok((tls_stream, _, _, _, mux_client_id, _)) => {
+                                let (mux_control, _) = new_mux_session(tls_stream, MuxMode::Server);
+                                if ctx.registry.add_mux(&client_name, &mux_client_id, mux_control.clone()) {
+                                    log::info!("mux channel connected: {} at {}", client_name, addr);
+                                } else {
+                                    log::error!("mux channel from unknown client: {}:{}", client_name, mux_client_id);
+                                    mux_control.close();
+                                }
+                            },
+                            Err(e) => {
+                                log::error!("Failed to open mux channel to {}: {}", addr, e);
+                                break;
+                            }
                         }
-                        
-                    } else {
-                        log::error!("Cannot find match binding for: {}", bind_id);
                     }
+                    sleep(Duration::from_secs(PASSIVE_CLIENT_CHECK_INTERVAL)).await;
                 }
+                log::info!("passive client {} at {} disconnected", client_name, addr);
             },
+            Err(e) => {
+                log::error!("Failed to connect passive client {}: {}", addr, e);
+            }
+        }
+
+        sleep(Duration::from_secs(PASSIVE_CLIENT_RECONNECT_TIMEOUT)).await;
+    }
+}
+
+/// 以证书身份为准确定客户端名称, 自报名称为空时使用证书名称, 与证书不一致时返回 None
+fn verify_client_name(declared: &str, identity: &CertIdentity) -> Option<String> {
+    let cert_name = identity.name()?;
+    if declared.is_empty() {
+        return Some(cert_name.to_string());
+    }
+
+    if identity.matches(declared) {
+        Some(declared.to_string())
+    } else {
+        None
+    }
+}
 
-            proxy_msg = proxy_rx.recv() => {
-                if let Some(msg) = proxy_msg {
-                    let (_id, _mapping_name, _tx) = msg;
-                    log::debug!("proxy new id: {}", _id);
-
-                    let proxy_mapping = option.mappings.iter().find(|x| x.name == _mapping_name).unwrap().clone();
-                    bind_queue.insert(_id.clone(), _tx);
-                    let clear_tx = clear_tx.clone();
-                    let cls_bind_id = _id.clone();
-                    tokio::spawn(async move {
-                        sleep(Duration::from_secs(FORWARD_CONNECTION_BIND_TIMEOUT)).await;
-                        if !clear_tx.is_closed() {
-                            clear_tx.send(cls_bind_id).await.unwrap();
+fn spawn_handshake(ctx: ServerContext, socket: TcpStream, peer_addr: SocketAddr, conn_tx: mpsc::Sender<HandshakeConn>) {
+    tokio::spawn(async move {
+        match timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), server_handshake(&ctx, socket)).await {
+            Ok(Ok((res, tls_stream, identity))) => {
+                conn_tx.send((res, tls_stream, peer_addr, identity)).await.unwrap_or(());
+            },
+            Ok(Err(e)) => {
+                log::error!("Failed to accept connection from {}: {}", peer_addr, e);
+            },
+            Err(_) => {
+                log::error!("Connection handshake timeout: {}", peer_addr);
+            }
+        }
+    });
+}
+
+/// 请求映射对应的客户端建立一条转发通道, 优先使用多路复用通道. 失败时返回响应状态
+async fn server_open_forward(ctx: &ServerContext, mapping: &MappingConfig, bind_id: &str, target: Option<String>) -> Result<ForwardConn, &'static str> {
+    let Some(client) = ctx.registry.pick(&mapping.client) else {
+        log::error!("proxy id: {} no client connected for mapping: {}", bind_id, mapping.name);
+        return Err(proto::STATUS_UNAVAILABLE);
+    };
+    ctx.sessions.set_route(bind_id, &mapping.name, &client.name);
+
+    let proto_body = proto::ProtoCmdBody::Conn(proto::ProxyRequest {
+        bind_id: bind_id.to_string(),
+        client: client.name.clone(),
+        mapping: Box::new(mapping.clone()),
+        token: bind_token(&ctx.bind_key, bind_id, &client.name),
+        target,
+    });
+    let req = proto::ProtoCmdRequest::new(proto_body);
+
+    if let Some(mux_control) = ctx.registry.pick_mux(&client.name) {
+        return server_request_forward(ctx, mapping, async { mux_control.open_stream() }, req, bind_id)
+            .await
+            .map(|stream| (bind_id.to_string(), client.name.clone(), stream, client.peer_addr));
+    }
+
+    if let Some(dial_addr) = &client.dial_addr {
+        let open = async {
+            let (tls_stream, identity, _) = server_dial(ctx, dial_addr, "data").await?;
+            if !identity.matches(&client.name) {
+                return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("unexpected client certificate {}", identity.subject)));
+            }
+            Ok(tls_stream)
+        };
+        return server_request_forward(ctx, mapping, open, req, bind_id)
+            .await
+            .map(|stream| (bind_id.to_string(), client.name.clone(), stream, client.peer_addr));
+    }
+
+    if !ctx.data_enabled {
+        log::error!("no mux channel or data port for proxy id: {}", bind_id);
+        return Err(proto::STATUS_UNAVAILABLE);
+    }
+
+    // 客户端先通过信令连接回复连接目标的结果, 成功后再建立数据连接
+    let (tx, mut rx) = oneshot::channel::<ForwardConn>();
+    ctx.bind_queue.lock().unwrap().insert(bind_id.to_string(), (client.name.clone(), tx));
+    let mut rsp_rx = client.pending.register(&req.id);
+    let result = if client.cmd_tx.send(proto::ProtoCmd::Request(req.clone())).await.is_err() {
+        log::error!("proxy id: {} client {} disconnected", bind_id, client.name);
+        Err(proto::STATUS_UNAVAILABLE)
+    } else {
+        let wait = async {
+            loop {
+                select! {
+                    conn = &mut rx => return conn.map_err(|_| proto::STATUS_UNAVAILABLE),
+                    rsp = &mut rsp_rx => match rsp {
+                        Ok(rsp) if rsp.is_ok() => {
+                            // 继续等待数据连接
+                            rsp_rx = client.pending.register(&req.id);
+                        },
+                        Ok(rsp) => {
+                            log::error!("proxy id: {} client forward failed: {}", bind_id, rsp.message);
+                            return Err(status_str(&rsp.status));
+                        },
+                        Err(_) => {
+                            log::error!("proxy id: {} client {} disconnected", bind_id, client.name);
+                            return Err(proto::STATUS_UNAVAILABLE);
                         }
-                        
-                    });
-                    
-                    let proto_body = proto::ProtoCmdBody::ProxyRequest { bind_id: _id.clone(), client: String::from("client1"), mapping: proxy_mapping};
-                    let reqcmd = proto::ProtoCmd::Request(proto::ProtoCmdRequest::new(String::from("conn"), Some(proto_body)));
-
-                    let json = serde_json::to_string(&reqcmd).unwrap();
-                    log::trace!("server: send data: {}", json);
-                    main_tls_stream.write(json.as_bytes()).await.unwrap();
-                    main_tls_stream.write(&META_MSG_END_FLAG).await.unwrap();
-                    main_tls_stream.flush().await.unwrap();
+                    },
                 }
-            },
-            clear_msg = clear_rx.recv() => {
-                if let Some(bind_id) = clear_msg {
-                    //TODO: optimize id clear
-                    if bind_queue.contains_key(&bind_id) {
-                        bind_queue.remove(&bind_id);
-                        log::error!("clear bind client: {}", bind_id);
+            }
+        };
+        match timeout(Duration::from_secs(FORWARD_CONNECTION_BIND_TIMEOUT), wait).await {
+            Ok(result) => result,
+            Err(_) => {
+                log::error!("proxy id: {} wait forward connection timeout", bind_id);
+                ctx.metrics.bind_timeout(&mapping.name);
+                Err(proto::STATUS_TIMEOUT)
+            }
+        }
+    };
+
+    client.pending.remove(&req.id);
+    if result.is_err() && ctx.bind_queue.lock().unwrap().remove(bind_id).is_some() {
+        log::debug!("clear bind client: {}", bind_id);
+    }
+    result
+}
+
+/// 响应中的状态, 未知状态按普通错误处理
+fn status_str(status: &str) -> &'static str {
+    [
+        proto::STATUS_CONNECTION_REFUSED,
+        proto::STATUS_HOST_UNREACHABLE,
+        proto::STATUS_TIMEOUT,
+        proto::STATUS_UNAVAILABLE,
+        proto::STATUS_UNSUPPORTED,
+    ].into_iter().find(|x| *x == status).unwrap_or(proto::STATUS_ERROR)
+}
+
+/// 打开一条转发流(多路复用流或被动模式的数据连接), 等待客户端连接目标后交给代理任务
+async fn server_request_forward<S, F>(ctx: &ServerContext, mapping: &MappingConfig, open: F, req: proto::ProtoCmdRequest, bind_id: &str) -> Result<BoxStream, &'static str>
+where S: AsyncStream + 'static, F: std::future::Future<Output = std::io::Result<S>> {
+    let open = async {
+        let mut stream = open.await?;
+        proto::write_cmd(&mut stream, &proto::ProtoCmd::Request(req.clone())).await?;
+        let rsp = proto::read_cmd_exact(&mut stream).await?;
+        Ok::<_, std::io::Error>((stream, rsp))
+    };
+
+    match timeout(Duration::from_secs(FORWARD_CONNECTION_BIND_TIMEOUT), open).await {
+        Ok(Ok((stream, proto::ProtoCmd::Response(rsp)))) if rsp.id == req.id => {
+            if !rsp.is_ok() {
+                log::error!("proxy id: {} client forward failed: {}", bind_id, rsp.message);
+                return Err(status_str(&rsp.status));
+            }
+            log::debug!("forward stream bind to id: {}", bind_id);
+            Ok(Box::new(stream))
+        },
+        Ok(Ok((_, cmd))) => {
+            log::error!("proxy id: {} unexpected forward response: {:?}", bind_id, cmd);
+            Err(proto::STATUS_ERROR)
+        },
+        Ok(Err(e)) => {
+            log::error!("proxy id: {} open forward stream error: {}", bind_id, e);
+            Err(proto::STATUS_UNAVAILABLE)
+        },
+        Err(_) => {
+            log::error!("proxy id: {} open forward stream timeout", bind_id);
+            ctx.metrics.bind_timeout(&mapping.name);
+            Err(proto::STATUS_TIMEOUT)
+        }
+    }
+}
+
+/// SOCKS5 代理: 在服务端完成握手, 由客户端连接请求的目标地址
+async fn server_socks5_forward(ctx: &ServerContext, mapping: &MappingConfig, bind_id: &str, socket: &mut TcpStream) -> Option<ForwardConn> {
+    let handshake = proxy::socks5_handshake(socket, ctx.proxy_pass.as_deref());
+    let target = match timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), handshake).await {
+        Ok(Ok(target)) => target,
+        Ok(Err(e)) => {
+            log::error!("proxy id: {} socks5 handshake error: {}", bind_id, e);
+            return None;
+        },
+        Err(_) => {
+            log::error!("proxy id: {} socks5 handshake timeout", bind_id);
+            return None;
+        }
+    };
+
+    log::debug!("proxy id: {} socks5 connect to {}", bind_id, target);
+    let forward_conn = server_open_forward(ctx, mapping, bind_id, Some(target)).await;
+    let rep = match forward_conn {
+        Ok(_) => proxy::SOCKS5_REP_SUCCEEDED,
+        Err(proto::STATUS_CONNECTION_REFUSED) => proxy::SOCKS5_REP_CONNECTION_REFUSED,
+        Err(proto::STATUS_TIMEOUT) => proxy::SOCKS5_REP_TTL_EXPIRED,
+        Err(_) => proxy::SOCKS5_REP_HOST_UNREACHABLE,
+    };
+    if let Err(e) = proxy::socks5_reply(socket, rep).await {
+        log::error!("proxy id: {} socks5 reply error: {}", bind_id, e);
+        return None;
+    }
+    forward_conn.ok()
+}
+
+/// 转发失败时返回给 HTTP 用户的响应
+fn http_error_response(status: &str) -> Vec<u8> {
+    match status {
+        proto::STATUS_TIMEOUT => proxy::http_response(504, "Gateway Timeout", &[]),
+        proto::STATUS_UNAVAILABLE => proxy::http_response(503, "Service Unavailable", &[]),
+        _ => proxy::http_response(502, "Bad Gateway", &[]),
+    }
+}
+
+/// HTTP 正向代理: 在服务端解析请求得到目标地址, 由客户端连接目标
+async fn server_http_forward(ctx: &ServerContext, mapping: &MappingConfig, bind_id: &str, socket: &mut TcpStream) -> Option<ForwardConn> {
+    let (head, rest) = match timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), proxy::read_http_head(socket)).await {
+        Ok(Ok(head)) => head,
+        Ok(Err(e)) => {
+            log::error!("proxy id: {} read http request error: {}", bind_id, e);
+            return None;
+        },
+        Err(_) => {
+            log::error!("proxy id: {} read http request timeout", bind_id);
+            return None;
+        }
+    };
+
+    let req = match proxy::HttpProxyRequest::parse(&head) {
+        Ok(req) => req,
+        Err(e) => {
+            log::error!("proxy id: {} {}", bind_id, e);
+            socket.write_all(&proxy::http_response(400, "Bad Request", &[])).await.unwrap_or(());
+            return None;
+        }
+    };
+
+    if let Some(proxy_pass) = &ctx.proxy_pass {
+        if !req.check_auth(proxy_pass) {
+            log::error!("proxy id: {} http proxy auth failed", bind_id);
+            let rsp = proxy::http_response(407, "Proxy Authentication Required", &[("Proxy-Authenticate", "Basic realm=\"natproxy\"")]);
+            socket.write_all(&rsp).await.unwrap_or(());
+            return None;
+        }
+    }
+
+    log::debug!("proxy id: {} http {} to {}", bind_id, if req.connect { "connect" } else { "request" }, req.target);
+    let (id, client, mut fw_stream, peer_addr) = match server_open_forward(ctx, mapping, bind_id, Some(req.target.clone())).await {
+        Ok(conn) => conn,
+        Err(status) => {
+            socket.write_all(&http_error_response(status)).await.unwrap_or(());
+            return None;
+        }
+    };
+
+    if req.connect {
+        let result = match socket.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await {
+            Ok(_) if !rest.is_empty() => fw_stream.write_all(&rest).await,
+            result => result,
+        };
+        if let Err(e) = result {
+            log::error!("proxy id: {} http proxy write error: {}", bind_id, e);
+            return None;
+        }
+        return Some((id, client, fw_stream, peer_addr));
+    }
+
+    let vars = proxy::HeaderVars {
+        client_ip: socket.peer_addr().map(|x| x.ip().to_string()).unwrap_or_default(),
+        url: req.url.clone(),
+        host: req.head.get("Host").unwrap_or_default().to_string(),
+        mapping: mapping.name.clone(),
+        client: client.clone(),
+    };
+    match server_http_send(fw_stream, req.head, &rest, mapping, vars).await {
+        Ok(fw_stream) => Some((id, client, fw_stream, peer_addr)),
+        Err(e) => {
+            log::error!("proxy id: {} http proxy write error: {}", bind_id, e);
+            None
+        }
+    }
+}
+
+/// 按映射的头部规则改写请求头后发送, 有响应规则时改写随后返回的响应头
+async fn server_http_send(mut fw_stream: BoxStream, mut head: proxy::HttpHead, rest: &[u8], mapping: &MappingConfig, vars: proxy::HeaderVars) -> std::io::Result<BoxStream> {
+    // 规则在启动时已经校验过
+    let rules = mapping.header_rules().unwrap_or_default();
+    rules.apply(proxy::HeaderScope::Request, &mut head, &vars);
+
+    let mut data = head.encode();
+    data.extend_from_slice(rest);
+    fw_stream.write_all(&data).await?;
+
+    if rules.has_scope(proxy::HeaderScope::Response) {
+        return Ok(Box::new(proxy::ResponseHeaderStream::new(fw_stream, rules, vars)));
+    }
+    Ok(fw_stream)
+}
+
+/// 反向代理: 按 Host 和路径前缀选择同一监听端口上的映射, 转发到映射的 forward 地址
+async fn server_httpreverse_forward(ctx: &ServerContext, mappings: &[MappingConfig], bind_id: &str, socket: &mut TcpStream) -> Option<ForwardConn> {
+    let (head, rest) = match timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), proxy::read_http_head(socket)).await {
+        Ok(Ok(head)) => head,
+        Ok(Err(e)) => {
+            log::error!("proxy id: {} read http request error: {}", bind_id, e);
+            return None;
+        },
+        Err(_) => {
+            log::error!("proxy id: {} read http request timeout", bind_id);
+            return None;
+        }
+    };
+
+    let route = proxy::HttpHead::parse(&head).and_then(|head| {
+        let route = proxy::reverse_request_route(&head)?;
+        Ok((head, route))
+    });
+    let (mut head, (host, path)) = match route {
+        Ok(route) => route,
+        Err(e) => {
+            log::error!("proxy id: {} {}", bind_id, e);
+            socket.write_all(&proxy::http_response(400, "Bad Request", &[])).await.unwrap_or(());
+            return None;
+        }
+    };
+
+    let Some(mapping) = proxy::match_virtual_host(mappings, &host, &path) else {
+        log::error!("proxy id: {} no mapping for host: {} path: {}", bind_id, host, path);
+        socket.write_all(&proxy::http_response(404, "Not Found", &[])).await.unwrap_or(());
+        return None;
+    };
+
+    log::debug!("proxy id: {} host: {} path: {} to mapping {}", bind_id, host, path, mapping.name);
+    let (id, client, fw_stream, peer_addr) = match server_open_forward(ctx, mapping, bind_id, None).await {
+        Ok(conn) => conn,
+        Err(status) => {
+            socket.write_all(&http_error_response(status)).await.unwrap_or(());
+            return None;
+        }
+    };
+
+    // 每个连接只转发一个请求, 同一连接上的后续请求可能属于其它映射.
+    // 协议升级后连接不再承载 HTTP 请求, 保留 Upgrade 相关头部
+    if !proxy::is_upgrade_request(&head) {
+        head.set("Connection", "close");
+    }
+    let host_header = head.get("Host").unwrap_or_default().to_string();
+    let vars = proxy::HeaderVars {
+        client_ip: socket.peer_addr().map(|x| x.ip().to_string()).unwrap_or_default(),
+        url: format!("http://{}{}", host_header, path),
+        host: host_header,
+        mapping: mapping.name.clone(),
+        client: client.clone(),
+    };
+    match server_http_send(fw_stream, head, &rest, mapping, vars).await {
+        Ok(fw_stream) => Some((id, client, fw_stream, peer_addr)),
+        Err(e) => {
+            log::error!("proxy id: {} http reverse write error: {}", bind_id, e);
+            None
+        }
+    }
+}
+
+/// HTTPS 代理: 读取 ClientHello 按 SNI 选择映射, 不终止 TLS, 原始数据转发到映射的 forward 地址
+async fn server_https_forward(ctx: &ServerContext, mappings: &[MappingConfig], bind_id: &str, socket: &mut TcpStream) -> Option<ForwardConn> {
+    let (hello, sni) = match timeout(Duration::from_secs(CONNECTION_HANDSHAKE_TIMEOUT), proxy::read_client_hello(socket)).await {
+        Ok(Ok(hello)) => hello,
+        Ok(Err(e)) => {
+            log::error!("proxy id: {} read tls client hello error: {}", bind_id, e);
+            return None;
+        },
+        Err(_) => {
+            log::error!("proxy id: {} read tls client hello timeout", bind_id);
+            return None;
+        }
+    };
+
+    let sni = sni.unwrap_or_default();
+    let Some(mapping) = proxy::match_virtual_host(mappings, &sni, "") else {
+        log::error!("proxy id: {} no mapping for sni: {}", bind_id, sni);
+        return None;
+    };
+
+    log::debug!("proxy id: {} sni: {} to mapping {}", bind_id, sni, mapping.name);
+    let (id, client, mut fw_stream, peer_addr) = server_open_forward(ctx, mapping, bind_id, None).await.ok()?;
+    if let Err(e) = fw_stream.write_all(&hello).await {
+        log::error!("proxy id: {} https write error: {}", bind_id, e);
+        return None;
+    }
+    Some((id, client, fw_stream, peer_addr))
+}
+
+/// UDP 映射: 按来源地址建立会话, 每个会话使用一条转发通道
+async fn server_udp_proxy(ctx: ServerContext, mappings: SharedMappings, socket: UdpSocket
+    , mut cli_rx: watch::Receiver<String>, mut stop_rx: watch::Receiver<String>) {
+    let socket = Arc::new(socket);
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut recv_buf = vec![0u8; proxy::MAX_DATAGRAM_SIZE];
+    loop {
+        select! {
+            recv_result = socket.recv_from(&mut recv_buf) => {
+                let Ok((len, src_addr)) = recv_result else {
+                    continue;
+                };
+                let data = recv_buf[..len].to_vec();
+                if let Some(tx) = sessions.get(&src_addr) {
+                    match tx.try_send(data) {
+                        Ok(_) => continue,
+                        // 队列已满时丢弃, 与 UDP 语义一致
+                        Err(mpsc::error::TrySendError::Full(_)) => continue,
+                        Err(mpsc::error::TrySendError::Closed(data)) => {
+                            sessions.remove(&src_addr);
+                            server_udp_new_session(&ctx, &mappings, &socket, &mut sessions, src_addr, data);
+                        }
                     }
+                } else {
+                    server_udp_new_session(&ctx, &mappings, &socket, &mut sessions, src_addr, data);
                 }
             },
-            _ = sleep(Duration::from_secs(MAIN_CONNECTION_KEEPALIVE_TIMEOUT)) => {
-                let reqcmd = proto::ProtoCmd::Request(proto::ProtoCmdRequest::new(String::from("keepalive"), None));
-
-                let json = serde_json::to_string(&reqcmd).unwrap();
-                log::trace!("server: send data: {}", json);
-                main_tls_stream.write(json.as_bytes()).await.unwrap();
-                main_tls_stream.write(&META_MSG_END_FLAG).await.unwrap();
-                main_tls_stream.flush().await.unwrap();
+            _ = cli_rx.changed() => {
+                log::debug!("udp proxy task recv app quit msg");
+                break;
+            },
+            _ = stop_rx.changed() => {
+                log::debug!("udp proxy task stopped");
+                break;
+            }
+        }
+    }
+    ctx.listeners.remove_group(&mappings);
+}
+
+fn server_udp_new_session(ctx: &ServerContext, mappings: &SharedMappings, socket: &Arc<UdpSocket>
+    , sessions: &mut HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>, src_addr: SocketAddr, data: Vec<u8>) {
+    let Some(mapping) = mappings.lock().unwrap().first().cloned() else {
+        return;
+    };
+    sessions.retain(|_, tx| !tx.is_closed());
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(UDP_SESSION_QUEUE_SIZE);
+    tx.try_send(data).unwrap_or(());
+    sessions.insert(src_addr, tx);
+    tokio::spawn(server_udp_session(ctx.clone(), mapping, socket.clone(), src_addr, rx));
+}
+
+async fn server_udp_session(ctx: ServerContext, mapping: MappingConfig, socket: Arc<UdpSocket>, src_addr: SocketAddr, mut rx: mpsc::Receiver<Vec<u8>>) {
+    let bind_id = generate_uuid();
+    log::debug!("new udp session bind id: {} from {}", bind_id, src_addr);
+    let session = ctx.sessions.open(&bind_id, src_addr.to_string());
+    let Ok((_, _, mut fw_stream, _)) = server_open_forward(&ctx, &mapping, &bind_id, None).await else {
+        log::error!("proccess tx[{}] no forward connection", bind_id);
+        return;
+    };
+    let counter = session.counter();
+
+    let mut buffer = Vec::new();
+    let result: std::io::Result<()> = async {
+        loop {
+            select! {
+                data = rx.recv() => {
+                    let Some(data) = data else {
+                        return Ok(());
+                    };
+                    counter.add_read(data.len() as u64);
+                    proxy::write_datagram(&mut fw_stream, &data).await?;
+                },
+                data = proxy::read_datagram(&mut fw_stream, &mut buffer) => {
+                    let Some(data) = data? else {
+                        return Ok(());
+                    };
+                    counter.add_written(socket.send_to(&data, src_addr).await? as u64);
+                },
+                _ = session.closed() => {
+                    log::info!("proccess tx[{}] closed by admin", bind_id);
+                    return Ok(());
+                },
+                _ = sleep(Duration::from_secs(proxy::UDP_SESSION_IDLE_TIMEOUT)) => {
+                    log::debug!("proccess tx[{}] udp session idle timeout", bind_id);
+                    return Ok(());
+                }
             }
-            
+        }
+    }.await;
+
+    match result {
+        Ok(_) => {
+            log::info!("proccess tx[{}] success", bind_id)
+        },
+        Err(e) => {
+            log::error!("proccess tx[{}] error: {}", bind_id, e)
         }
     }
+}
 
+/// 按映射模式建立转发通道, 共享监听地址的映射需要先从请求中识别目标映射
+async fn server_proxy_forward(ctx: &ServerContext, mappings: &[MappingConfig], bind_id: &str, socket: &mut TcpStream) -> Option<ForwardConn> {
+    let mapping = &mappings[0];
+    if mapping.is_socks5() {
+        server_socks5_forward(ctx, mapping, bind_id, socket).await
+    } else if mapping.is_http() {
+        server_http_forward(ctx, mapping, bind_id, socket).await
+    } else if mapping.is_httpreverse() {
+        server_httpreverse_forward(ctx, mappings, bind_id, socket).await
+    } else if mapping.is_https() {
+        server_https_forward(ctx, mappings, bind_id, socket).await
+    } else {
+        server_open_forward(ctx, mapping, bind_id, None).await.ok()
+    }
 }
 
-async fn server_start_proxy(mappings:& Vec<MappingConfig>
-    , proxy_tx: mpsc::Sender<(String, String, oneshot::Sender<(String, String, TlsServerStream<TcpStream>, SocketAddr)>)>
+/// 开始监听映射, `owner` 为声明映射的客户端, 配置文件中的映射为 None.
+/// 模式未启用或配置不完整的映射跳过, 监听失败时返回错误
+async fn server_start_proxy(mappings: &[MappingConfig]
+    , owner: Option<&str>
+    , ctx: ServerContext
     , maincli_rx: watch::Receiver<String>
 ) -> Result<(), tokio::io::Error> {
     for mapping in mappings {
-        let cli_rx = maincli_rx.clone();
-        let proxy_listener = TcpListener::bind(mapping.listen.unwrap()).await.unwrap();
-        let proxy_tx2 = proxy_tx.clone();
-        let mapping_name = mapping.name.clone();
+        if let Err(reason) = ctx.check_mapping(mapping) {
+            log::error!("mapping {} {}, skip", mapping.name, reason);
+            ctx.listeners.set_inactive(mapping, owner, ListenerState::Skipped, reason);
+            continue;
+        }
+        if let Err(e) = server_listen_mapping(&ctx, mapping, owner, maincli_rx.clone()).await {
+            log::error!("mapping {} listen error: {}", mapping.name, e);
+            ctx.listeners.set_inactive(mapping, owner, ListenerState::Failed, e.to_string());
+            return Err(e);
+        }
+    }
+    Ok(())
+}
 
-        tokio::spawn(async move {
-            let mut cli_rx = cli_rx.clone();
-            loop {
-                select! {
-                    accept_result = proxy_listener.accept() => {
-                        let (mut _socket, mut _peer_addr) = accept_result.unwrap();
-                        let bind_id = generate_uuid();
-                        let proxy_tx2 = proxy_tx2.clone();
-                        let mapping_name = mapping_name.clone();
-
-                        log::debug!("new bind id: {}", bind_id);
-                        tokio::spawn(async move {
-                            let (tx, rx) = oneshot::channel::<(String, String, TlsServerStream<TcpStream>, SocketAddr)>();
-                            let proxy_tx2 = proxy_tx2.clone();
-                            proxy_tx2.send((bind_id.clone(), mapping_name, tx)).await.unwrap();   
-                            let (_id, _client_id, mut _fw_socket, _fw_peer_addr) = rx.await.unwrap();
-                            log::trace!("start process id: {} ------------", bind_id);
-                            let result = server_data_forward(&mut _fw_socket, &mut _socket).await;
-                            match result {
-                                Ok(size) => {
-                                    log::info!("proccess tx[{}] success", bind_id)
-                                },
-                                Err(e) => {
-                                    let err_kind = e.kind();
-                                    match err_kind {
-                                        std::io::ErrorKind::UnexpectedEof => {
-                                            log::info!("proccess tx[{}] connection close", bind_id);
-                                        },
-                                        _ => {
-                                            log::error!("proccess tx[{}] error: {}", bind_id, e);
-                                        }
-                                    }
-                                    
-                                }
-                            }
-                        });
-                    },
-                    cmd_msg = cli_rx.changed() => {
-                        log::debug!("proxy task recv app quit msg");
-                        break;
-                    }
-                }
-                
-            }
-        });
+enum ProxySocket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+/// 监听单个映射. 同一地址上已有同模式的虚拟主机监听时加入该监听, 其它映射各自独占监听地址
+async fn server_listen_mapping(ctx: &ServerContext, mapping: &MappingConfig, owner: Option<&str>, cli_rx: watch::Receiver<String>) -> std::io::Result<()> {
+    let Some(listen) = mapping.listen else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no listen address"));
+    };
+    if ctx.listeners.join_group(mapping, owner) {
+        log::info!("mapping {} joins listener on {}", mapping.name, listen);
+        return Ok(());
     }
 
+    let mut retries = 0;
+    let socket = loop {
+        let bind_result = if mapping.is_udp() {
+            udp_bind(listen).map(ProxySocket::Udp)
+        } else {
+            tcp_listen(listen).map(ProxySocket::Tcp)
+        };
+        match bind_result {
+            Ok(socket) => break socket,
+            // 刚移除的映射或重连客户端旧连接的监听可能还没有关闭
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && retries < MAPPING_BIND_RETRIES => {
+                retries += 1;
+                sleep(Duration::from_millis(MAPPING_BIND_RETRY_DELAY)).await;
+            },
+            Err(e) => return Err(e),
+        }
+    };
+
+    log::info!("mapping {} listen on {}", mapping.name, listen);
+    let (mappings, stop_rx) = ctx.listeners.add_group(listen, mapping, owner);
+    match socket {
+        ProxySocket::Tcp(listener) => tokio::spawn(server_tcp_proxy(ctx.clone(), mappings, listener, cli_rx, stop_rx)),
+        ProxySocket::Udp(socket) => tokio::spawn(server_udp_proxy(ctx.clone(), mappings, socket, cli_rx, stop_rx)),
+    };
     Ok(())
 }
 
-async fn server_data_forward(tls_stream:&mut TlsServerStream<TcpStream>, tcp_stream: &mut TcpStream) -> Result<usize, tokio::io::Error>  {
-    let mut tls_recv_buffer:[u8; 1024] = [0; 1024];
-    let mut dst_recv_buffer:[u8; 1024] = [0; 1024];
-    log::trace!("start forward data ....");
+/// TCP 映射的监听任务, 每个连接使用接受时的映射列表
+async fn server_tcp_proxy(ctx: ServerContext, mappings: SharedMappings, listener: TcpListener
+    , mut cli_rx: watch::Receiver<String>, mut stop_rx: watch::Receiver<String>) {
     loop {
-
         select! {
-            dst_res = tcp_stream.read(&mut dst_recv_buffer) => {
-                match dst_res {
-                    Ok(size) => {
-                        if size > 0 {
-                            log::trace!("send data to forward size:{}", size);
-                            tls_stream.write_all(&dst_recv_buffer[0..size]).await.unwrap();
-                            tls_stream.flush().await.unwrap();
-                            log::debug!("sended data to forward size:{}", size);
-                        } else {
-                            //log::trace!("proxy client closed");
-                            //break;
-                        }
-                    },
+            accept_result = listener.accept() => {
+                let (socket, peer_addr) = match accept_result {
+                    Ok(conn) => conn,
                     Err(e) => {
-                        let err_kind = e.kind();
-                        match err_kind {
-                            std::io::ErrorKind::UnexpectedEof => {
-                                log::info!("tcp stream connection closed");
-                                break;
-                            },
-                            _ => {
-                                log::error!("read from tcp stream error: {}", e);
-                                return Err(e);
-                            }
-                        }
+                        log::error!("Failed to accept proxy connection: {}", e);
+                        sleep(Duration::from_millis(ACCEPT_ERROR_DELAY)).await;
+                        continue;
                     }
+                };
+                let current = mappings.lock().unwrap().clone();
+                if current.is_empty() {
+                    continue;
                 }
+                tokio::spawn(server_proxy_conn(ctx.clone(), current, socket, peer_addr));
             },
-            fwd_res = tls_stream.read(&mut tls_recv_buffer) => {
-                match fwd_res {
-                    Ok(size) => {
-                        if size > 0 {
-                            log::trace!("send data to source size:{}", size);
-                            tcp_stream.write_all(&tls_recv_buffer[0..size]).await.unwrap();
-                            tcp_stream.flush().await.unwrap();
-                            log::trace!("sended data to source size:{}", size);
-                        } else {
-                            //log::trace!("forward connection closed");
-                            //break;
-                        }
-                    },
-                    Err(e) => {
-                        let err_kind = e.kind();
-                        match err_kind {
-                            std::io::ErrorKind::UnexpectedEof => {
-                                log::debug!("tls stream connection closed");
-                                break;
-                            },
-                            _ => {
-                                log::error!("read from tls stream error: {}", e);
-                                return Err(e);
-                            }
-                        } 
-                    }
+            _ = cli_rx.changed() => {
+                log::debug!("proxy task recv app quit msg");
+                break;
+            },
+            _ = stop_rx.changed() => {
+                log::debug!("proxy task stopped");
+                break;
+            }
+        }
+    }
+    ctx.listeners.remove_group(&mappings);
+}
+
+async fn server_proxy_conn(ctx: ServerContext, mappings: Vec<MappingConfig>, mut socket: TcpStream, peer_addr: SocketAddr) {
+    let bind_id = generate_uuid();
+    log::debug!("new bind id: {}", bind_id);
+    let session = ctx.sessions.open(&bind_id, peer_addr.to_string());
+    let forward_conn = select! {
+        forward_conn = server_proxy_forward(&ctx, &mappings, &bind_id, &mut socket) => forward_conn,
+        _ = session.closed() => None,
+    };
+    let Some((_id, _client_id, mut fw_socket, _fw_peer_addr)) = forward_conn else {
+        log::error!("proccess tx[{}] no forward connection", bind_id);
+        return;
+    };
+    log::trace!("start process id: {} ------------", bind_id);
+    let mut socket = CountedStream::new(socket, session.counter());
+    let result = select! {
+        result = stream_forward(&mut fw_socket, &mut socket) => result,
+        _ = session.closed() => {
+            log::info!("proccess tx[{}] closed by admin", bind_id);
+            return;
+        }
+    };
+    match result {
+        Ok(_) => {
+            log::info!("proccess tx[{}] success", bind_id)
+        },
+        Err(e) => {
+            let err_kind = e.kind();
+            match err_kind {
+                std::io::ErrorKind::UnexpectedEof => {
+                    log::info!("proccess tx[{}] connection close", bind_id);
+                },
+                _ => {
+                    log::error!("proccess tx[{}] error: {}", bind_id, e);
                 }
             }
-            
         }
     }
-    
-    Ok(0)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_token_is_bound_to_client() {
+        let key = new_bind_key();
+        let token = bind_token(&key, "bind1", "client1");
+        assert_eq!(token.len(), 64);
+        assert!(verify_bind_token(&key, "bind1", "client1", &token));
+        assert!(!verify_bind_token(&key, "bind1", "client2", &token));
+        assert!(!verify_bind_token(&key, "bind2", "client1", &token));
+        assert!(!verify_bind_token(&new_bind_key(), "bind1", "client1", &token));
+    }
+
+    #[test]
+    fn tampered_bind_token_is_rejected() {
+        let key = new_bind_key();
+        let token = bind_token(&key, "bind1", "client1");
+        let last = if token.ends_with('0') { "1" } else { "0" };
+        let tampered = format!("{}{}", &token[..token.len() - 1], last);
+        assert!(!verify_bind_token(&key, "bind1", "client1", &tampered));
+        assert!(!verify_bind_token(&key, "bind1", "client1", &token[..token.len() - 1]));
+        assert!(!verify_bind_token(&key, "bind1", "client1", &format!("{}zz", &token[..token.len() - 2])));
+        assert!(!verify_bind_token(&key, "bind1", "client1", ""));
+    }
+
+    #[test]
+    fn decode_hex_values() {
+        assert_eq!(decode_hex("00ff1a"), Some(vec![0x00, 0xff, 0x1a]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
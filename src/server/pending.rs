@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::proto;
+
+/// 等待客户端响应的请求, 以 `ProtoCmdRequest.id` 为键
+#[derive(Clone, Default)]
+pub struct PendingRequests {
+    requests: Arc<Mutex<HashMap<String, oneshot::Sender<proto::ProtoCmdResponse>>>>,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 登记请求, 响应到达时通过返回的通道通知. 等待方超时后需要调用 `remove`
+    pub fn register(&self, id: &str) -> oneshot::Receiver<proto::ProtoCmdResponse> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.lock().unwrap().insert(id.to_string(), tx);
+        rx
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.requests.lock().unwrap().remove(id);
+    }
+
+    /// 把响应交给等待的请求, 没有对应请求时返回 false
+    pub fn complete(&self, rsp: proto::ProtoCmdResponse) -> bool {
+        let Some(tx) = self.requests.lock().unwrap().remove(&rsp.id) else {
+            return false;
+        };
+        tx.send(rsp).is_ok()
+    }
+
+    /// 连接断开时结束所有等待
+    pub fn clear(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    fn response(id: &str) -> proto::ProtoCmdResponse {
+        proto::ProtoCmdResponse::new(id.to_string(), proto::CmdKind::Conn, String::from(proto::STATUS_OK), String::new())
+    }
+
+    #[tokio::test]
+    async fn response_is_routed_by_id() {
+        let pending = PendingRequests::new();
+        let rx1 = pending.register("id1");
+        let rx2 = pending.register("id2");
+
+        assert!(pending.complete(response("id2")));
+        assert!(pending.complete(response("id1")));
+        assert_eq!(rx1.await.unwrap().id, "id1");
+        assert_eq!(rx2.await.unwrap().id, "id2");
+
+        // 每个请求只接受一次响应
+        assert!(!pending.complete(response("id1")));
+        assert!(!pending.complete(response("unknown")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_request_is_removed() {
+        let pending = PendingRequests::new();
+        let rx = pending.register("id1");
+        assert!(timeout(Duration::from_secs(5), rx).await.is_err());
+        pending.remove("id1");
+        assert!(!pending.complete(response("id1")));
+    }
+
+    #[tokio::test]
+    async fn clear_cancels_waiting_requests() {
+        let pending = PendingRequests::new();
+        let rx = pending.register("id1");
+        pending.clear();
+        assert!(rx.await.is_err());
+        assert!(!pending.complete(response("id1")));
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, Notify};
+
+use crate::mux::MuxControl;
+use crate::proto;
+use crate::CertIdentity;
+
+use super::PendingRequests;
+
+/// 已连接客户端的信令通道句柄
+#[derive(Clone)]
+pub struct ClientHandle {
+    pub name: String,
+    /// 每次连接生成的ID, 用于区分同名客户端的新旧连接
+    pub client_id: String,
+    pub peer_addr: SocketAddr,
+    /// 客户端证书身份, 客户端名称由它决定
+    pub identity: CertIdentity,
+    pub cmd_tx: mpsc::Sender<proto::ProtoCmd>,
+    pub shutdown: Arc<Notify>,
+    /// 被动模式下客户端的监听地址, 数据连接由服务端向该地址发起
+    pub dial_addr: Option<String>,
+    /// 与客户端协商的协议版本
+    pub version: u32,
+    pub connected_at: String,
+    /// 通过信令连接发出, 等待客户端响应的请求
+    pub pending: PendingRequests,
+}
+
+struct ClientEntry {
+    handle: ClientHandle,
+    mux_sessions: Vec<MuxControl>,
+    mux_next: usize,
+}
+
+/// 以客户端名称为键的注册表
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+    clients: Arc<Mutex<HashMap<String, ClientEntry>>>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 注册客户端, 同名的旧连接会被关闭
+    pub fn register(&self, handle: ClientHandle) {
+        let old = self.clients.lock().unwrap().insert(handle.name.clone(), ClientEntry {
+            handle,
+            mux_sessions: vec![],
+            mux_next: 0,
+        });
+
+        if let Some(old) = old {
+            log::info!("client {} reconnected, close old connection {}", old.handle.name, old.handle.client_id);
+            Self::close_entry(&old);
+        }
+    }
+
+    /// 注销客户端, 只有连接ID一致时才会移除, 避免误删重连后的新连接
+    pub fn unregister(&self, name: &str, client_id: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.get(name).map(|x| x.handle.client_id == client_id).unwrap_or(false) {
+            if let Some(entry) = clients.remove(name) {
+                Self::close_entry(&entry);
+            }
+        }
+    }
+
+    /// 主动断开客户端, 客户端不存在时返回 false
+    pub fn disconnect(&self, name: &str) -> bool {
+        let Some(entry) = self.clients.lock().unwrap().remove(name) else {
+            return false;
+        };
+        log::info!("disconnect client {} connection {}", name, entry.handle.client_id);
+        Self::close_entry(&entry);
+        true
+    }
+
+    /// 所有已连接的客户端及其可用的多路复用通道数量, 按名称排序
+    pub fn list(&self) -> Vec<(ClientHandle, usize)> {
+        let mut clients = self.clients.lock().unwrap();
+        let mut list: Vec<(ClientHandle, usize)> = clients.values_mut().map(|entry| {
+            entry.mux_sessions.retain(|x| !x.is_closed());
+            (entry.handle.clone(), entry.mux_sessions.len())
+        }).collect();
+        list.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        list
+    }
+
+    pub fn get(&self, name: &str) -> Option<ClientHandle> {
+        self.clients.lock().unwrap().get(name).map(|x| x.handle.clone())
+    }
+
+    /// 按名称查找客户端, 名称为空时任选一个已连接的客户端
+    pub fn pick(&self, name: &str) -> Option<ClientHandle> {
+        if !name.is_empty() {
+            return self.get(name);
+        }
+
+        let clients = self.clients.lock().unwrap();
+        let mut names: Vec<&String> = clients.keys().collect();
+        names.sort();
+        names.first().map(|x| clients[*x].handle.clone())
+    }
+
+    pub fn add_mux(&self, name: &str, client_id: &str, mux_control: MuxControl) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        match clients.get_mut(name) {
+            Some(entry) if entry.handle.client_id == client_id => {
+                entry.mux_sessions.push(mux_control);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// 客户端当前可用的多路复用通道数量, 客户端未注册或已重连时返回 None
+    pub fn mux_count(&self, name: &str, client_id: &str) -> Option<usize> {
+        let mut clients = self.clients.lock().unwrap();
+        let entry = clients.get_mut(name).filter(|x| x.handle.client_id == client_id)?;
+        entry.mux_sessions.retain(|x| !x.is_closed());
+        Some(entry.mux_sessions.len())
+    }
+
+    /// 轮询选择客户端的一个多路复用通道
+    pub fn pick_mux(&self, name: &str) -> Option<MuxControl> {
+        let mut clients = self.clients.lock().unwrap();
+        let entry = clients.get_mut(name)?;
+        entry.mux_sessions.retain(|x| !x.is_closed());
+        if entry.mux_sessions.is_empty() {
+            return None;
+        }
+
+        entry.mux_next = (entry.mux_next + 1) % entry.mux_sessions.len();
+        Some(entry.mux_sessions[entry.mux_next].clone())
+    }
+
+    fn close_entry(entry: &ClientEntry) {
+        entry.handle.shutdown.notify_one();
+        for mux_control in &entry.mux_sessions {
+            mux_control.close();
+        }
+    }
+}
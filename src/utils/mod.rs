@@ -2,8 +2,12 @@ mod util_tls;
 mod util_net;
 mod util_date;
 mod util_string;
+mod util_stream;
+mod util_backoff;
 
 pub use util_tls::*;
 pub use util_net::*;
 pub use util_date::*;
-pub use util_string::*;
\ No newline at end of file
+pub use util_string::*;
+pub use util_stream::*;
+pub use util_backoff::*;
\ No newline at end of file
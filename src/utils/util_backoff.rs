@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// 指数退避, 每次失败后等待时间乘以 `multiplier`, 不超过 `max_delay`
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    /// 随机抖动比例, 0.2 表示在 [0.8, 1.2] 倍之间浮动
+    jitter: f64,
+    current: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(delay: Duration, max_delay: Duration, multiplier: f64, jitter: f64) -> Backoff {
+        Backoff {
+            delay,
+            max_delay,
+            multiplier,
+            jitter,
+            current: delay,
+            attempts: 0,
+        }
+    }
+
+    /// 连续失败的次数
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// 返回本次等待时间并增加下一次的等待时间
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current.min(self.max_delay);
+        self.attempts += 1;
+        // 按 f64 计算, 溢出或非有限值时取上限, 避免 `Duration::mul_f64` panic
+        self.current = Duration::try_from_secs_f64(delay.as_secs_f64() * self.multiplier)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        Duration::try_from_secs_f64(delay.as_secs_f64() * (1.0 + self.jitter * random_unit())).unwrap_or(delay)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.delay;
+        self.attempts = 0;
+    }
+}
+
+/// [-1, 1) 之间的随机数
+fn random_unit() -> f64 {
+    let mut buf = [0u8; 4];
+    if SystemRandom::new().fill(&mut buf).is_err() {
+        return 0.0;
+    }
+    u32::from_be_bytes(buf) as f64 / (u32::MAX as f64 + 1.0) * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_until_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 2.0, 0.0);
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.attempts(), 6);
+    }
+
+    #[test]
+    fn reset_restores_initial_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 3.0, 0.0);
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn huge_multiplier_is_capped() {
+        let max_delay = Duration::from_secs(300);
+        for multiplier in [f64::MAX, f64::INFINITY, f64::NAN] {
+            let mut backoff = Backoff::new(Duration::from_secs(1), max_delay, multiplier, 0.0);
+            backoff.next_delay();
+            assert_eq!(backoff.next_delay(), max_delay);
+        }
+
+        let mut backoff = Backoff::new(Duration::MAX, Duration::MAX, 2.0, 1.0);
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+    }
+
+    #[test]
+    fn jitter_stays_in_range() {
+        let mut backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(10), 1.0, 0.2);
+        for _ in 0..1000 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(8) && delay <= Duration::from_secs(12), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn random_unit_range() {
+        for _ in 0..1000 {
+            let value = random_unit();
+            assert!((-1.0..1.0).contains(&value));
+        }
+    }
+}
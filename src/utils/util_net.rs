@@ -1,15 +1,238 @@
+use std::collections::HashMap;
+use std::io;
 use std::net::{
     SocketAddr,
     ToSocketAddrs,
 };
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant};
 
-pub fn lookup_ipv4(host: &str, port: u16) -> SocketAddr {
-    let addrs = (host, port).to_socket_addrs().unwrap();
-    for addr in addrs {
-        if let SocketAddr::V4(_) = addr {
-            return addr;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+
+/// 域名解析结果的缓存时间
+const DNS_CACHE_TTL: u64 = 60;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// 域名解析得到多个地址时优先使用的地址族
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IpFamily {
+    /// 保持系统解析顺序
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl FromStr for IpFamily {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "" | "any" => Ok(IpFamily::Any),
+            "ipv4" => Ok(IpFamily::Ipv4),
+            "ipv6" => Ok(IpFamily::Ipv6),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid ip family {:?}, expect any, ipv4 or ipv6", s))),
         }
     }
+}
+
+impl IpFamily {
+    /// 按优先的地址族排序, 同一地址族内保持原有顺序
+    pub fn sort(&self, addrs: &mut [SocketAddr]) {
+        match self {
+            IpFamily::Any => {},
+            IpFamily::Ipv4 => addrs.sort_by_key(|x| !x.is_ipv4()),
+            IpFamily::Ipv6 => addrs.sort_by_key(|x| !x.is_ipv6()),
+        }
+    }
+}
+
+static PREFER_FAMILY: AtomicU8 = AtomicU8::new(0);
+
+/// 设置 `resolve_addrs` 的地址族优先级, 启动时根据配置设置
+pub fn set_prefer_family(family: IpFamily) {
+    PREFER_FAMILY.store(family as u8, Ordering::Relaxed);
+}
+
+pub fn prefer_family() -> IpFamily {
+    match PREFER_FAMILY.load(Ordering::Relaxed) {
+        1 => IpFamily::Ipv4,
+        2 => IpFamily::Ipv6,
+        _ => IpFamily::Any,
+    }
+}
+
+/// 同步解析主机名, 返回全部 IPv4/IPv6 地址, 按 `prefer` 排序
+pub fn lookup_addrs(host: &str, port: u16, prefer: IpFamily) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", host)));
+    }
+    prefer.sort(&mut addrs);
+    Ok(addrs)
+}
+
+/// 拆分 `host`, `host:port`, `[ipv6]:port` 或 IPv6 字面量, 未指定端口时返回 `None`
+pub fn split_host_port(addr: &str) -> io::Result<(String, Option<u16>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address {:?}", addr));
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+        }
+    } else if addr.matches(':').count() > 1 {
+        // 不带方括号的 IPv6 地址不能携带端口
+        (addr, None)
+    } else {
+        match addr.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let port = port.map(|x| x.parse::<u16>().map_err(|_| invalid())).transpose()?;
+    Ok((host.to_string(), port))
+}
+
+/// 组合 `host:port`, IPv6 地址加上方括号
+pub fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// 域名 -> (过期时间, 地址列表)
+type DnsCache = HashMap<String, (Instant, Vec<SocketAddr>)>;
+
+fn dns_cache() -> &'static Mutex<DnsCache> {
+    static DNS_CACHE: OnceLock<Mutex<DnsCache>> = OnceLock::new();
+    DNS_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn dns_cache_get(cache: &DnsCache, target: &str, now: Instant) -> Option<Vec<SocketAddr>> {
+    cache.get(target).filter(|(expire, _)| *expire > now).map(|(_, addrs)| addrs.clone())
+}
+
+/// 写入缓存, 同时清理已过期的记录
+fn dns_cache_insert(cache: &mut DnsCache, target: &str, addrs: Vec<SocketAddr>, now: Instant) {
+    cache.retain(|_, (expire, _)| *expire > now);
+    cache.insert(target.to_string(), (now + Duration::from_secs(DNS_CACHE_TTL), addrs));
+}
 
-    unreachable!("Cannot lookup address");
-}
\ No newline at end of file
+/// 异步解析 `host:port`, 返回全部 IPv4/IPv6 地址, 按 `prefer_family` 排序.
+///
+/// `use_cache` 时结果按 TTL 缓存, 服务端等节点地址不使用缓存, 每次重新解析以便跟随 DNS 变化
+pub async fn resolve_addrs(target: &str, use_cache: bool) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+
+    if use_cache {
+        if let Some(mut addrs) = dns_cache_get(&dns_cache().lock().unwrap(), target, Instant::now()) {
+            prefer_family().sort(&mut addrs);
+            return Ok(addrs);
+        }
+    }
+
+    let mut addrs: Vec<SocketAddr> = lookup_host(target).await
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}: {}", target, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", target)));
+    }
+
+    if use_cache {
+        dns_cache_insert(&mut dns_cache().lock().unwrap(), target, addrs.clone(), Instant::now());
+    }
+    prefer_family().sort(&mut addrs);
+    Ok(addrs)
+}
+
+/// 连接 `host:port`, 依次尝试解析出的每个地址, `use_cache` 同 [`resolve_addrs`]
+pub async fn tcp_connect(target: &str, use_cache: bool) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in resolve_addrs(target, use_cache).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                log::debug!("connect to {} ({}) error: {}", target, addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", target))))
+}
+
+/// 按地址创建 socket, 监听 `[::]` 时同时接收 IPv4 连接
+fn dual_stack_socket(addr: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(not(windows))]
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    Ok(socket)
+}
+
+/// 监听 TCP 地址
+pub fn tcp_listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = dual_stack_socket(&addr, Type::STREAM, Protocol::TCP)?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// 绑定 UDP 地址
+pub fn udp_bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = dual_stack_socket(&addr, Type::DGRAM, Protocol::UDP)?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dns_cache_expires_after_ttl() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+        let addrs: Vec<SocketAddr> = vec!["192.0.2.1:80".parse().unwrap(), "[2001:db8::1]:80".parse().unwrap()];
+        dns_cache_insert(&mut cache, "example.com:80", addrs.clone(), now);
+
+        assert_eq!(dns_cache_get(&cache, "example.com:80", now), Some(addrs.clone()));
+        let ttl = Duration::from_secs(DNS_CACHE_TTL);
+        assert_eq!(dns_cache_get(&cache, "example.com:80", now + ttl - Duration::from_secs(1)), Some(addrs));
+        assert_eq!(dns_cache_get(&cache, "example.com:80", now + ttl), None);
+        assert_eq!(dns_cache_get(&cache, "example.org:80", now), None);
+    }
+
+    #[test]
+    fn dns_cache_insert_drops_expired_entries() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+        dns_cache_insert(&mut cache, "a:80", vec!["192.0.2.1:80".parse().unwrap()], now);
+        let later = now + Duration::from_secs(DNS_CACHE_TTL + 1);
+        dns_cache_insert(&mut cache, "b:80", vec!["192.0.2.2:80".parse().unwrap()], later);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains_key("b:80"));
+    }
+
+    #[tokio::test]
+    async fn resolve_ip_literal_without_lookup() {
+        assert_eq!(resolve_addrs("127.0.0.1:80", true).await.unwrap(), vec!["127.0.0.1:80".parse().unwrap()]);
+        assert_eq!(resolve_addrs("[::1]:443", true).await.unwrap(), vec!["[::1]:443".parse().unwrap()]);
+    }
+}
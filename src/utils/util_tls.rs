@@ -2,60 +2,66 @@ use std::fs::File;
 use std::io;
 use std::io::BufReader;
 use std::sync::Arc;
+use serde::Serialize;
 use rustls::{
     RootCertStore,
     server::AllowAnyAuthenticatedClient,
 };
-use tokio::{net::TcpStream, io::AsyncReadExt};
+use tokio::net::TcpStream;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 use tokio_rustls::{
     TlsAcceptor,
     TlsConnector,
     rustls::{self},
     client::TlsStream as TlsClientStream,
-    server::TlsStream as TlsServerStream,
 };
-use tokio::select;
-fn load_certs(filename: &str) -> Vec<rustls::Certificate> {
-    let certfile = File::open(filename).expect("cannot open certificate file");
+
+use super::tcp_connect;
+
+fn tls_error<E: std::fmt::Display>(file: &str, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e))
+}
+
+fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
+    let certfile = File::open(filename).map_err(|e| tls_error(filename, e))?;
     let mut reader = BufReader::new(certfile);
-    rustls_pemfile::certs(&mut reader)
-        .unwrap()
-        .iter()
-        .map(|v| rustls::Certificate(v.clone()))
-        .collect()
+    let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut reader)
+        .map_err(|e| tls_error(filename, e))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(tls_error(filename, "no certificates found"));
+    }
+    Ok(certs)
 }
 
-fn load_private_key(filename: &str) -> rustls::PrivateKey {
-    let keyfile = File::open(filename).expect("cannot open private key file");
+fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
+    let keyfile = File::open(filename).map_err(|e| tls_error(filename, e))?;
     let mut reader = BufReader::new(keyfile);
 
     loop {
-        match rustls_pemfile::read_one(&mut reader).expect("cannot parse private key .pem file") {
-            Some(rustls_pemfile::Item::RSAKey(key)) => return rustls::PrivateKey(key),
-            Some(rustls_pemfile::Item::PKCS8Key(key)) => return rustls::PrivateKey(key),
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| tls_error(filename, e))? {
+            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(rustls::PrivateKey(key)),
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(rustls::PrivateKey(key)),
             None => break,
             _ => {}
         }
     }
 
-    panic!(
-        "no keys found in {:?} (encrypted keys not supported)",
-        filename
-    );
+    Err(tls_error(filename, "no keys found (encrypted keys not supported)"))
 }
 
-fn make_client_config(ca_file: &str, certs_file: &str, key_file: &str) -> Arc<rustls::ClientConfig> {
-    let cert_file = File::open(&ca_file).expect("Cannot open CA file");
-    let mut reader = BufReader::new(cert_file);
-
+fn make_client_config(ca_file: &str, certs_file: &str, key_file: &str) -> io::Result<Arc<rustls::ClientConfig>> {
     let mut root_store = RootCertStore::empty();
-    root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut reader).unwrap());
+    let roots: Vec<Vec<u8>> = load_certs(ca_file)?.into_iter().map(|x| x.0).collect();
+    root_store.add_parsable_certificates(&roots);
 
     let suites = rustls::DEFAULT_CIPHER_SUITES.to_vec();
     let versions = rustls::DEFAULT_VERSIONS.to_vec();
 
-    let certs = load_certs(certs_file);
-    let key = load_private_key(key_file);
+    let certs = load_certs(certs_file)?;
+    let key = load_private_key(key_file)?;
 
     let config = rustls::ClientConfig::builder()
         .with_cipher_suites(&suites)
@@ -64,21 +70,21 @@ fn make_client_config(ca_file: &str, certs_file: &str, key_file: &str) -> Arc<ru
         .expect("inconsistent cipher-suite/versions selected")
         .with_root_certificates(root_store)
         .with_client_auth_cert(certs, key)
-        .expect("invalid client auth certs/key");
-    Arc::new(config)
+        .map_err(|e| tls_error(key_file, e))?;
+    Ok(Arc::new(config))
 }
 
-fn make_server_config(ca_file: &str, certs_file: &str, key_file: &str) -> Arc<rustls::ServerConfig> {
+fn make_server_config(ca_file: &str, certs_file: &str, key_file: &str) -> io::Result<Arc<rustls::ServerConfig>> {
     
-    let roots = load_certs(ca_file);
-    let certs = load_certs(certs_file);
+    let roots = load_certs(ca_file)?;
+    let certs = load_certs(certs_file)?;
     let mut client_auth_roots = RootCertStore::empty();
     for root in roots {
-        client_auth_roots.add(&root).unwrap();
+        client_auth_roots.add(&root).map_err(|e| tls_error(ca_file, e))?;
     }
     let client_auth = AllowAnyAuthenticatedClient::new(client_auth_roots);
 
-    let privkey = load_private_key(key_file);
+    let privkey = load_private_key(key_file)?;
     let suites = rustls::ALL_CIPHER_SUITES.to_vec();
     let versions = rustls::ALL_VERSIONS.to_vec();
 
@@ -89,72 +95,88 @@ fn make_server_config(ca_file: &str, certs_file: &str, key_file: &str) -> Arc<ru
         .expect("inconsistent cipher-suites/versions specified")
         .with_client_cert_verifier(client_auth.boxed())
         .with_single_cert_with_ocsp_and_sct(certs, privkey, vec![], vec![])
-        .expect("bad certificates/private key");
+        .map_err(|e| tls_error(key_file, e))?;
 
     config.key_log = Arc::new(rustls::KeyLogFile::new());
     config.session_storage = rustls::server::ServerSessionMemoryCache::new(256);
-    Arc::new(config)
+    Ok(Arc::new(config))
 }
 
-pub async fn new_tls_stream(domain: &str, addr: std::net::SocketAddr, 
-    ca_file: &str, cert_file: &str, key_file: &str) -> TlsClientStream<TcpStream> {
-    let config = make_client_config(&ca_file, &cert_file, &key_file);
+/// 连接 `host:port` 并完成 TLS 握手, `domain` 用于校验服务端证书
+pub async fn new_tls_stream(domain: &str, addr: &str,
+    ca_file: &str, cert_file: &str, key_file: &str) -> io::Result<TlsClientStream<TcpStream>> {
+    let stream = tcp_connect(addr, false).await?;
+    tls_client_handshake(domain, stream, ca_file, cert_file, key_file).await
+}
 
-    let connector = TlsConnector::from(config);
+/// 在已建立的 TCP 连接上以客户端身份完成 TLS 握手, 被动模式下连接由服务端发起
+pub async fn tls_client_handshake(domain: &str, stream: TcpStream,
+    ca_file: &str, cert_file: &str, key_file: &str) -> io::Result<TlsClientStream<TcpStream>> {
+    let connector = new_tls_connector(ca_file, cert_file, key_file)?;
+    let domain = rustls::ServerName::try_from(domain).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?;
+    connector.connect(domain, stream).await
+}
 
-    let stream = TcpStream::connect(&addr).await.unwrap();
-    let domain = rustls::ServerName::try_from(domain).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname")).unwrap();
-    let stream = connector.connect(domain, stream).await.unwrap();
-    stream
+/// 客户端 TLS 配置, 每次建立连接时重新读取证书文件
+pub fn new_tls_connector(ca_file: &str, cert_file: &str, key_file: &str) -> io::Result<TlsConnector> {
+    make_client_config(ca_file, cert_file, key_file).map(TlsConnector::from)
 }
 
-pub fn new_tls_acceptor(ca_file: &str, cert_file: &str, key_file: &str) -> TlsAcceptor {
-    let config = make_server_config(&ca_file, &cert_file, &key_file);
-    let acceptor = TlsAcceptor::from(config);
-    acceptor
+pub fn new_tls_acceptor(ca_file: &str, cert_file: &str, key_file: &str) -> io::Result<TlsAcceptor> {
+    make_server_config(ca_file, cert_file, key_file).map(TlsAcceptor::from)
 }
 
 
-pub async fn tls_server_read_to(tls_stream: &mut TlsServerStream<TcpStream>, buffer: &mut Vec<u8>, end_byte:u8) -> io::Result<usize> {
-    let mut buf:[u8; 1] = [0; 1];
-    loop {
-        let result = tls_stream.read_exact(&mut buf).await;
-        match result {
-            Ok(size) => {
-                if size == 0 {
-                    break;
-                }
-                if buf[0] == end_byte {
-                    return Ok(buffer.len());
-                }
-                buffer.push(buf[0]);
-            }
-            Err(e) => return Err(e),
-        }
+/// 从证书中提取的身份信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CertIdentity {
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub not_after: String,
+}
+
+impl CertIdentity {
+    /// 身份名称, 优先使用 CN, 其次是第一个 DNS SAN
+    pub fn name(&self) -> Option<&str> {
+        self.common_name.as_deref().or(self.dns_names.first().map(|x| x.as_str()))
     }
 
-    io::Result::Ok(buffer.len())
+    pub fn matches(&self, name: &str) -> bool {
+        self.common_name.as_deref() == Some(name) || self.dns_names.iter().any(|x| x == name)
+    }
 }
 
-pub async fn tls_client_read_to(tls_stream: &mut TlsClientStream<TcpStream>, buffer: &mut Vec<u8>, end_byte:u8) -> io::Result<usize> {
-    let mut buf:[u8; 1] = [0; 1];
-    loop {
-        let result = tls_stream.read_exact(&mut buf).await;
-        match result {
-            Ok(size) => {
-                if size == 0 {
-                    break;
-                }
-                
-                if buf[0] == end_byte {
-                    return Ok(buffer.len());
-                }
-
-                buffer.push(buf[0]);
+pub fn parse_cert_identity(cert: &rustls::Certificate) -> Option<CertIdentity> {
+    let (_, x509) = X509Certificate::from_der(&cert.0).ok()?;
+    let common_name = x509.subject()
+        .iter_common_name()
+        .next()
+        .and_then(|x| x.as_str().ok())
+        .map(|x| x.to_string());
+
+    let mut dns_names = vec![];
+    if let Ok(Some(san)) = x509.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(dns) = name {
+                dns_names.push(dns.to_string());
             }
-            Err(e) => return Err(e),
         }
     }
 
-    io::Result::Ok(buffer.len())
+    Some(CertIdentity {
+        common_name,
+        dns_names,
+        subject: x509.subject().to_string(),
+        issuer: x509.issuer().to_string(),
+        serial: x509.raw_serial_as_string(),
+        not_after: x509.validity().not_after.to_string(),
+    })
+}
+
+/// 读取本地证书文件的身份信息
+pub fn load_cert_identity(cert_file: &str) -> Option<CertIdentity> {
+    load_certs(cert_file).ok()?.first().and_then(parse_cert_identity)
 }